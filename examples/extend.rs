@@ -4,7 +4,7 @@
 extern crate lua;
 
 mod config {
-    use std::{convert::TryFrom, fs::File, io::Read, path::Path};
+    use std::{convert::TryFrom, path::Path};
 
     #[derive(Debug)]
     pub struct Config {
@@ -30,7 +30,7 @@ mod config {
         fn try_from(state: &mut lua::State) -> Result<Self, Self::Error> {
             let tp = state.get_global("background")?;
             match tp {
-                lua::types::LUA_TSTRING => {
+                lua::Type::String => {
                     let v = state.as_bytes(-1);
                     let name = std::str::from_utf8(v)?;
                     for (key, red, green, blue) in COLOR_TABLE.iter().cloned() {
@@ -43,7 +43,7 @@ mod config {
                         format!("invalid color name ({})", name),
                     ))
                 }
-                lua::types::LUA_TTABLE => {
+                lua::Type::Table => {
                     let red = get_color_field(state, "red")?;
                     let green = get_color_field(state, "green")?;
                     let blue = get_color_field(state, "blue")?;
@@ -80,11 +80,7 @@ mod config {
         pub fn open<P: AsRef<Path>>(path: P, state: &mut lua::State) -> lua::Result<Self> {
             let mut state = lua::state::StackGuard::new(state);
 
-            let mut file = File::open(path)?;
-            let mut buf = Vec::with_capacity(1_024);
-            file.read_to_end(&mut buf)?;
-
-            state.load_string(buf)?;
+            state.load_file(path)?;
             state.pcall(0, 0, 0)?;
 
             Ok(Self {
@@ -1,8 +1,6 @@
 //! Calling C from Lua
 extern crate lua;
 
-use std::{fs::File, io::Read};
-
 use lua::state::{RustFunction, Pull};
 
 fn main() -> lua::Result<()> {
@@ -19,11 +17,7 @@ fn main() -> lua::Result<()> {
     state.push(func)?;
     state.set_global("mysin")?;
 
-    let mut file = File::open("examples/func.lua")?;
-    let mut buf = Vec::with_capacity(1_024);
-    file.read_to_end(&mut buf)?;
-
-    state.load_string(buf)?;
+    state.load_file("examples/func.lua")?;
     state.pcall(0, 0, 0)?;
 
     state.get_global("width")?;
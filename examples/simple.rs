@@ -15,16 +15,12 @@ fn main() -> lua::Result<()> {
     for line in stdin.lock().lines() {
         let line = line.unwrap();
 
-        let ret = state.load_string(line).and_then(|_| {
-            state.pcall(0, 0, 0)
-        });
-
-        if let Err(e) = ret {
+        if let Err(e) = state.do_string(line) {
             println!("{}", e);
-
-            // pope error message
-            state.pop(-1);
         }
+
+        // discards the results or the error message
+        state.set_top(0);
     }
 
     Ok(())
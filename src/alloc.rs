@@ -4,6 +4,8 @@ use std::{alloc, ptr};
 
 use libc::c_void;
 
+use crate::ffi;
+
 // Copied from https://github.com/rust-lang/rust/blob/master/src/libstd/sys_common/alloc.rs
 #[cfg(all(any(
     target_arch = "x86",
@@ -15,7 +17,7 @@ use libc::c_void;
     target_arch = "wasm32",
     target_arch = "hexagon"
 )))]
-const SYS_MIN_ALIGN: usize = 8;
+pub(crate) const SYS_MIN_ALIGN: usize = 8;
 #[cfg(all(any(
     target_arch = "x86_64",
     target_arch = "aarch64",
@@ -24,13 +26,14 @@ const SYS_MIN_ALIGN: usize = 8;
     target_arch = "sparc64",
     target_arch = "riscv64"
 )))]
-const SYS_MIN_ALIGN: usize = 16;
+pub(crate) const SYS_MIN_ALIGN: usize = 16;
 
 /// MemoryInfo keeps track of the memory being used by Lau.
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MemoryInfo {
     used: isize,
     limit: isize,
+    peak: isize,
 }
 
 /// Allocate memory with the global allocator.
@@ -77,6 +80,7 @@ pub unsafe extern "C" fn alloc(
         let p = alloc::alloc(new_layout);
         if !p.is_null() {
             info.used += diff_size;
+            info.peak = info.peak.max(info.used);
         }
         return p as *mut c_void;
     }
@@ -87,6 +91,7 @@ pub unsafe extern "C" fn alloc(
 
     if !p.is_null() {
         info.used += diff_size;
+        info.peak = info.peak.max(info.used);
     } else if !ptr.is_null() && new_size < old_size {
         // should not happen, still ...
         alloc::handle_alloc_error(new_layout);
@@ -101,6 +106,70 @@ impl MemoryInfo {
         Self {
             limit: limit as isize,
             used: 0,
+            peak: 0,
+        }
+    }
+
+    /// Returns the number of bytes currently allocated.
+    pub fn used(&self) -> usize {
+        self.used as usize
+    }
+
+    /// Returns the highest number of bytes allocated at once since the state was created.
+    pub fn peak(&self) -> usize {
+        self.peak as usize
+    }
+}
+
+/// A memory allocator for Lua states, see [`State::with_allocator`](crate::State::with_allocator).
+///
+/// Lua calls the allocator for all its allocations, so the allocator must not panic: a panic
+/// cannot unwind through Lua and aborts the process.
+///
+/// # Safety
+///
+/// The blocks returned by `alloc` and `realloc` must be valid for reads and writes of `size` bytes
+/// and aligned as the blocks of `malloc`. Lua assumes that `realloc` never fails when shrinking a
+/// block, that is when `new_size` is not greater than `old_size`.
+pub unsafe trait LuaAllocator {
+    /// Allocates a new block of `size` bytes, returning a null pointer on failure.
+    fn alloc(&mut self, size: usize) -> *mut u8;
+
+    /// Resizes the block `ptr` of `old_size` bytes to `new_size` bytes, returning the address of
+    /// the resized block or a null pointer on failure, in which case `ptr` is left unchanged.
+    fn realloc(&mut self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8;
+
+    /// Frees the block `ptr` of `size` bytes.
+    fn free(&mut self, ptr: *mut u8, size: usize);
+}
+
+/// Allocate memory with the boxed [`LuaAllocator`] pointed to by `ud`.
+pub unsafe extern "C" fn alloc_with(
+    ud: *mut c_void,
+    ptr: *mut c_void,
+    old_size: usize,
+    new_size: usize,
+) -> *mut c_void {
+    trace!("alloc_with() old_size={}, new_size={}", old_size, new_size);
+
+    let allocator = &mut **(ud as *mut Box<dyn LuaAllocator + Send>);
+    if new_size == 0 {
+        if !ptr.is_null() {
+            allocator.free(ptr as *mut u8, old_size);
         }
+        ptr::null_mut()
+    } else if ptr.is_null() {
+        allocator.alloc(new_size) as *mut c_void
+    } else {
+        allocator.realloc(ptr as *mut u8, old_size, new_size) as *mut c_void
+    }
+}
+
+/// Frees the user data `ud` given to `allocf` when creating a Lua state, once the state is closed.
+pub unsafe fn free_userdata(allocf: ffi::lua_Alloc, ud: *mut c_void) {
+    if allocf as usize == alloc as ffi::lua_Alloc as usize {
+        drop(Box::from_raw(ud as *mut MemoryInfo));
+    } else if allocf as usize == alloc_with as ffi::lua_Alloc as usize {
+        drop(Box::from_raw(ud as *mut Box<dyn LuaAllocator + Send>));
     }
 }
@@ -6,6 +6,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// The error type for Lua operations.
 pub struct Error {
     kind: ErrorKind,
+    code: Option<i32>,
     error: Box<dyn std::error::Error + Send + Sync>,
 }
 
@@ -46,14 +47,113 @@ impl Error {
         Self::_new(kind, error.into())
     }
 
+    /// Creates a new Lua error from a Lua status code, such as `LUA_ERRRUN` or `LUA_ERRSYNTAX`,
+    /// as well as an arbitrary error payload.
+    pub(crate) fn with_code<E>(kind: ErrorKind, code: i32, error: E) -> Self
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Self {
+            code: Some(code),
+            ..Self::new(kind, error)
+        }
+    }
+
     fn _new(kind: ErrorKind, error: Box<dyn std::error::Error + Send + Sync>) -> Self {
-        Self { kind, error }
+        Self {
+            kind,
+            code: None,
+            error,
+        }
+    }
+
+    /// Returns the corresponding [`ErrorKind`] for this error.
+    ///
+    /// The [`Display`](fmt::Display) output of an error is its payload only, which is also the
+    /// message raised in Lua when a Rust function fails; logs wanting the category can combine
+    /// both, as the kind displays as a short description.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{Error, ErrorKind};
+    ///
+    /// let error = Error::new(ErrorKind::InvalidData, "invalid number");
+    /// assert_eq!(error.kind(), ErrorKind::InvalidData);
+    /// assert_eq!(error.to_string(), "invalid number");
+    /// assert_eq!(format!("{}: {}", error.kind(), error), "invalid data: invalid number");
+    /// ```
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns the Lua status code (see [`state::status`]) when this error was raised by Lua,
+    /// otherwise returns `None`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::status::{LUA_ERRRUN, LUA_ERRSYNTAX};
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    ///
+    /// let error = state.load_string("x = = 1").unwrap_err();
+    /// assert_eq!(error.lua_code(), Some(LUA_ERRSYNTAX));
+    /// state.pop(1);
+    ///
+    /// state.load_string("error('boom')").unwrap();
+    /// let error = state.pcall(0, 0, 0).unwrap_err();
+    /// assert_eq!(error.lua_code(), Some(LUA_ERRRUN));
+    /// ```
+    ///
+    /// [`state::status`]: crate::state::status
+    pub fn lua_code(&self) -> Option<i32> {
+        self.code
+    }
+
+    /// Consumes the error, returning its error payload.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{Error, ErrorKind};
+    ///
+    /// fn describe(error: Error) -> String {
+    ///     match error.kind() {
+    ///         ErrorKind::InvalidInput => format!("bad input: {}", error.into_inner()),
+    ///         _ => format!("failure: {}", error.into_inner()),
+    ///     }
+    /// }
+    ///
+    /// assert_eq!(describe(Error::new(ErrorKind::InvalidInput, "negative")), "bad input: negative");
+    /// assert_eq!(describe(Error::from("unexpected")), "failure: unexpected");
+    /// assert_eq!(Error::from(String::from("unexpected")).kind(), ErrorKind::Other);
+    /// ```
+    pub fn into_inner(self) -> Box<dyn std::error::Error + Send + Sync> {
+        self.error
     }
 }
 
+/// The source of a Lua error is its error payload, so wrapped errors can be recovered.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use std::{error::Error as _, io};
+///
+/// let error = lua::Error::from(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+/// let source = error.source().unwrap();
+/// let io_error = source.downcast_ref::<io::Error>().unwrap();
+/// assert_eq!(io_error.kind(), io::ErrorKind::NotFound);
+/// ```
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        self.error.source()
+        Some(&*self.error)
     }
 }
 
@@ -61,6 +161,7 @@ impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Error")
             .field("kind", &self.kind)
+            .field("code", &self.code)
             .field("error", &self.error)
             .finish()
     }
@@ -91,3 +192,5 @@ impl_from_errors!(
     ),
     InvalidData
 );
+
+impl_from_errors!((String, &str), Other);
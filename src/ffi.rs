@@ -21,6 +21,8 @@ pub type lua_KFunction =
     unsafe extern "C" fn(state: *mut lua_State, status: c_int, ctx: lua_KContext) -> c_int;
 pub type lua_CFunction = unsafe extern "C" fn(state: *mut lua_State) -> c_int;
 pub type lua_Hook = unsafe extern "C" fn(state: *mut lua_State, ar: *mut lua_Debug);
+pub type lua_WarnFunction =
+    unsafe extern "C" fn(ud: *mut c_void, msg: *const c_char, tocont: c_int);
 
 #[repr(C)]
 pub struct lua_Debug {
@@ -43,6 +45,8 @@ pub struct lua_Debug {
     i_ci: *mut c_void,
 }
 
+pub const LUA_VERSION_NUM: c_int = 504;
+
 pub const LUA_OK: c_int = 0;
 pub const LUA_YIELD: c_int = 1;
 pub const LUA_ERRRUN: c_int = 2;
@@ -60,6 +64,8 @@ pub const LUA_RIDX_MAINTHREAD: lua_Integer = 1;
 pub const LUA_RIDX_GLOBALS: lua_Integer = 2;
 pub const LUA_IDSIZE: c_int = 60;
 pub const LUA_MINSTACK: c_int = 20;
+pub const LUA_LOADED_TABLE: &str = "_LOADED";
+pub const LUA_PRELOAD_TABLE: &str = "_PRELOAD";
 // Not actually defined in lua.h / luaconf.h
 pub const LUA_MAX_UPVALUES: c_int = 255;
 
@@ -96,6 +102,7 @@ pub const LUA_MASKCOUNT: c_int = 8;
 extern "C" {
     pub fn lua_newstate(alloc: lua_Alloc, ud: *mut c_void) -> *mut lua_State;
     pub fn lua_close(state: *mut lua_State);
+    pub fn lua_getallocf(state: *mut lua_State, ud: *mut *mut c_void) -> lua_Alloc;
     pub fn lua_version(state: *mut lua_State) -> lua_Number;
 
     pub fn lua_callk(
@@ -120,6 +127,14 @@ extern "C" {
         nresults: *mut c_int,
     ) -> c_int;
     pub fn lua_status(state: *mut lua_State) -> c_int;
+    pub fn lua_resetthread(state: *mut lua_State) -> c_int;
+    pub fn lua_isyieldable(state: *mut lua_State) -> c_int;
+    pub fn lua_yieldk(
+        state: *mut lua_State,
+        nresults: c_int,
+        ctx: lua_KContext,
+        k: Option<lua_KFunction>,
+    ) -> c_int;
 
     pub fn lua_pushnil(state: *mut lua_State);
     pub fn lua_pushvalue(state: *mut lua_State, index: c_int);
@@ -140,7 +155,7 @@ extern "C" {
     pub fn lua_touserdata(state: *mut lua_State, index: c_int) -> *mut c_void;
     pub fn lua_tothread(state: *mut lua_State, index: c_int) -> *mut lua_State;
     pub fn lua_topointer(state: *mut lua_State, index: c_int) -> *const c_void;
-    pub fn lua_tocfunction(state: *mut lua_State, index: c_int) -> lua_CFunction;
+    pub fn lua_tocfunction(state: *mut lua_State, index: c_int) -> Option<lua_CFunction>;
     pub fn lua_toclose(state: *mut lua_State, index: c_int);
 
     pub fn lua_gettop(state: *const lua_State) -> c_int;
@@ -183,6 +198,7 @@ extern "C" {
     pub fn lua_setmetatable(state: *mut lua_State, index: c_int);
     pub fn lua_setfield(state: *mut lua_State, index: c_int, k: *const c_char);
 
+    pub fn lua_concat(state: *mut lua_State, n: c_int);
     pub fn lua_len(state: *mut lua_State, index: c_int);
     pub fn lua_rawlen(state: *mut lua_State, index: c_int) -> lua_Unsigned;
     pub fn lua_next(state: *mut lua_State, index: c_int) -> c_int;
@@ -193,6 +209,9 @@ extern "C" {
     pub fn lua_gc(state: *mut lua_State, what: c_int, ...) -> c_int;
     pub fn lua_getinfo(state: *mut lua_State, what: *const c_char, ar: *mut lua_Debug) -> c_int;
 
+    pub fn lua_setwarnf(state: *mut lua_State, f: Option<lua_WarnFunction>, ud: *mut c_void);
+    pub fn lua_warning(state: *mut lua_State, msg: *const c_char, tocont: c_int);
+
     pub fn lua_setcstacklimit(state: *mut lua_State, limit: c_uint) -> c_int;
     pub fn lua_sethook(state: *mut lua_State, f: Option<lua_Hook>, mask: c_int, count: c_int);
 
@@ -215,6 +234,7 @@ extern "C" {
         openf: lua_CFunction,
         glb: c_int,
     );
+    pub fn luaL_getsubtable(state: *mut lua_State, index: c_int, fname: *const c_char) -> c_int;
 
     pub fn luaL_loadbufferx(
         state: *mut lua_State,
@@ -234,6 +254,7 @@ extern "C" {
         level: c_int,
     );
     pub fn luaL_len(push_state: *mut lua_State, index: c_int) -> lua_Integer;
+    pub fn luaL_where(state: *mut lua_State, level: c_int);
     pub fn luaL_tolstring(state: *mut lua_State, index: c_int, len: *mut usize) -> *const c_char;
 
     pub fn lua_getglobal(state: *mut lua_State, name: *const c_char) -> c_int;
@@ -243,6 +264,8 @@ extern "C" {
     pub fn luaL_checklstring(state: *mut lua_State, arg: i32, len: *mut usize) -> *const c_char;
     pub fn luaL_checknumber(state: *mut lua_State, arg: i32) -> lua_Number;
     pub fn luaL_checkudata(state: *mut lua_State, arg: i32, tname: *const c_char) -> *mut c_void;
+    pub fn luaL_checktype(state: *mut lua_State, arg: i32, t: c_int);
+    pub fn luaL_optinteger(state: *mut lua_State, arg: i32, def: lua_Integer) -> lua_Integer;
 
 }
 
@@ -336,6 +359,14 @@ pub unsafe fn lua_isnone(state: *mut lua_State, index: c_int) -> c_int {
     }
 }
 
+pub unsafe fn lua_isnoneornil(state: *mut lua_State, index: c_int) -> c_int {
+    if lua_type(state, index) <= 0 {
+        1
+    } else {
+        0
+    }
+}
+
 pub unsafe fn lua_insert(state: *mut lua_State, index: c_int) {
     lua_rotate(state, index, 1);
 }
@@ -358,6 +389,10 @@ pub unsafe fn lua_pcall(
     lua_pcallk(state, nargs, nresults, msgh, ptr::null_mut(), None)
 }
 
+pub unsafe fn lua_yield(state: *mut lua_State, nresults: c_int) -> c_int {
+    lua_yieldk(state, nresults, ptr::null_mut(), None)
+}
+
 pub unsafe fn lua_replace(state: *mut lua_State, index: c_int) {
     lua_copy(state, -1, index);
     lua_pop(state, 1);
@@ -1,16 +1,22 @@
 #![feature(box_syntax)]
 #![feature(unboxed_closures)]
 #![feature(fn_traits)]
+#![feature(never_type)]
 
 #[macro_use]
 extern crate log;
 
+pub use self::alloc::LuaAllocator;
 pub use self::error::{Error, ErrorKind, Result};
-pub use self::state::{types, State};
+pub use self::state::{consts, status, types, State, Type};
 
 #[doc(hidden)]
 pub mod ffi;
 
 mod alloc;
 mod error;
+pub mod pool;
 pub mod state;
+
+/// The version number of the Lua headers this crate is built against, e.g. `504` for Lua 5.4.
+pub const LUA_VERSION_NUM: i32 = ffi::LUA_VERSION_NUM;
@@ -0,0 +1,251 @@
+//! Pool of reusable Lua states.
+use std::{
+    cell::RefCell,
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{error::Result, ffi, state::State};
+
+/// The registry field holding the snapshot of the tables of a pooled state, mapping each table
+/// to a shallow copy of its initial content.
+const GLOBALS_SNAPSHOT: &str = "lua.pool.globals";
+
+/// A bounded pool of pre-initialized Lua states, that is states with the standard libraries opened.
+///
+/// States are handed out as [`PooledState`] guards. When a guard gets out of scope its state is
+/// reset and returned to the pool, rather than being closed, as long as the pool holds less than
+/// `capacity` idle states.
+///
+/// Resetting a state clears its stack and restores its tables to the snapshot taken when the state
+/// was initialized: fields added by a script are removed, fields replaced or removed by a script
+/// are restored and metatables set by a script are removed. The restored tables are the global
+/// table, the tables stored in it (the standard libraries, e.g. `string.foo = 1` is undone), the
+/// table of loaded modules (`package.loaded`) and the metatable of strings. Changes made inside
+/// other tables, such as a table stored in a library, metatables set with the `debug` library on
+/// other types and values stored in the registry are kept.
+///
+/// A `StatePool` is not thread-safe; keep one pool per thread, for instance in a `thread_local!`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use lua::pool::StatePool;
+///
+/// let pool = StatePool::new(2);
+///
+/// let ptr = {
+///     let mut state = pool.get().unwrap();
+///     state.load_string("leaked = 1").unwrap();
+///     state.pcall(0, 0, 0).unwrap();
+///     format!("{:p}", *state)
+/// };
+///
+/// let mut state = pool.get().unwrap();
+/// assert_eq!(format!("{:p}", *state), ptr);
+/// state.get_global("leaked").unwrap();
+/// assert!(state.is_nil(-1));
+/// ```
+///
+/// Changes to the standard libraries, to the loaded modules and to the metatable of the globals
+/// are undone as well:
+///
+/// ```
+/// # extern crate lua;
+/// use lua::pool::StatePool;
+///
+/// let pool = StatePool::new(1);
+/// {
+///     let mut state = pool.get().unwrap();
+///     state.do_string(r#"
+///         setmetatable(_G, { __index = function() return "leaked" end })
+///         string.secret = "leaked"
+///         package.loaded.m = "leaked"
+///         getmetatable("").extra = "leaked"
+///     "#).unwrap();
+/// }
+///
+/// let mut state = pool.get().unwrap();
+/// state.do_string(r#"
+///     return getmetatable(_G), string.secret, package.loaded.m, getmetatable("").extra, undefined
+/// "#).unwrap();
+/// assert_eq!(state.top(), 5);
+/// assert!((1..=5).all(|i| state.is_nil(i)));
+/// ```
+pub struct StatePool {
+    capacity: usize,
+    states: RefCell<Vec<State>>,
+}
+
+impl StatePool {
+    /// Creates a new, empty `StatePool` keeping at most `capacity` idle states.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            states: RefCell::new(Vec::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the number of idle states in the pool.
+    pub fn len(&self) -> usize {
+        self.states.borrow().len()
+    }
+
+    /// Returns `true` if the pool holds no idle state.
+    pub fn is_empty(&self) -> bool {
+        self.states.borrow().is_empty()
+    }
+
+    /// Checks out a state from the pool, initializing a new one when the pool is empty.
+    pub fn get(&self) -> Result<PooledState<'_>> {
+        let state = self.states.borrow_mut().pop();
+        let state = match state {
+            Some(state) => state,
+            None => Self::init()?,
+        };
+        Ok(PooledState {
+            pool: self,
+            state: Some(state),
+        })
+    }
+
+    /// Creates a new state with the standard libraries opened and takes a snapshot of its tables.
+    fn init() -> Result<State> {
+        let mut state = State::new();
+        state.open_libs();
+
+        state.new_table()?;
+        state.raw_get_index(ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+        Self::snapshot(&mut state, 1, 2)?;
+
+        // the tables stored in the globals, that is the standard libraries
+        state.push_nil();
+        while state.next(2) {
+            if state.is_table(-1) {
+                Self::snapshot(&mut state, 1, -1)?;
+            }
+            state.pop(1);
+        }
+        state.pop(1);
+
+        state.get_field(ffi::LUA_REGISTRYINDEX, ffi::LUA_LOADED_TABLE)?;
+        Self::snapshot(&mut state, 1, -1)?;
+        state.pop(1);
+
+        state.push_string("")?;
+        if state.get_metatable(-1) {
+            Self::snapshot(&mut state, 1, -1)?;
+            state.pop(1);
+        }
+        state.pop(1);
+
+        state.set_field(ffi::LUA_REGISTRYINDEX, GLOBALS_SNAPSHOT)?;
+        Ok(state)
+    }
+
+    /// Stores a shallow copy of the table at `index` in the snapshot table at the absolute index
+    /// `snapshot`, with the table itself as key.
+    fn snapshot(state: &mut State, snapshot: i32, index: i32) -> Result<()> {
+        let index = state.abs_index(index);
+        state.push_value(index);
+        state.new_table()?;
+        state.push_nil();
+        while state.next(index) {
+            state.push_value(-2);
+            state.insert(-2);
+            state.raw_set(-4);
+        }
+        state.raw_set(snapshot);
+        Ok(())
+    }
+
+    /// Clears the stack of the state and restores its tables from the snapshot.
+    fn reset(state: &mut State) -> Result<()> {
+        state.set_top(0);
+        state.get_field(ffi::LUA_REGISTRYINDEX, GLOBALS_SNAPSHOT)?;
+        state.push_nil();
+        while state.next(1) {
+            Self::restore(state, 2, 3);
+            state.pop(1);
+        }
+        state.set_top(0);
+        Ok(())
+    }
+
+    /// Restores the table at the absolute index `index` from its copy at the absolute index `copy`.
+    fn restore(state: &mut State, index: i32, copy: i32) {
+        state.push_nil();
+        state.set_metatable(index);
+
+        // remove the fields which are not in the copy
+        state.push_nil();
+        while state.next(index) {
+            state.pop(1);
+            state.push_value(-1);
+            state.raw_get(copy);
+            if state.is_nil(-1) {
+                state.push_value(-2);
+                state.insert(-2);
+                state.raw_set(index);
+            } else {
+                state.pop(1);
+            }
+        }
+
+        // restore the fields from the copy
+        state.push_nil();
+        while state.next(copy) {
+            state.push_value(-2);
+            state.insert(-2);
+            state.raw_set(index);
+        }
+    }
+}
+
+impl fmt::Debug for StatePool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatePool")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// A state checked out from a [`StatePool`], which is reset and returned to the pool when it gets
+/// out of scope.
+#[derive(Debug)]
+pub struct PooledState<'a> {
+    pool: &'a StatePool,
+    state: Option<State>,
+}
+
+impl<'a> Drop for PooledState<'a> {
+    fn drop(&mut self) {
+        let mut state = match self.state.take() {
+            Some(state) => state,
+            None => return,
+        };
+        if let Err(e) = StatePool::reset(&mut state) {
+            error!("[StatePool] failed to reset state, {}", e);
+            return;
+        }
+        let mut states = self.pool.states.borrow_mut();
+        if states.len() < self.pool.capacity {
+            states.push(state);
+        }
+    }
+}
+
+impl<'a> Deref for PooledState<'a> {
+    type Target = State;
+    fn deref(&self) -> &Self::Target {
+        self.state.as_ref().expect("pooled state already returned")
+    }
+}
+
+impl<'a> DerefMut for PooledState<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.state.as_mut().expect("pooled state already returned")
+    }
+}
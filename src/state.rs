@@ -1,27 +1,33 @@
 //! Lua state.
 use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
     ffi::{CStr, CString},
-    fmt,
+    fmt, fs,
+    hash::Hash,
+    iter::FromIterator,
     marker::PhantomData,
     mem,
     ops::{Deref, DerefMut},
+    path::Path,
     ptr::{self, null, NonNull},
 };
 
 use crate::{
-    alloc,
+    alloc::{self, LuaAllocator},
     error::{Error, ErrorKind, Result},
     ffi,
 };
 
 use libc::c_void;
+pub use status::*;
 pub use types::*;
 
 pub mod types {
     use super::ffi;
 
-    /// The type returned by [`Stack::value_type`](crate::Stack::value_type) when a non-valid but
-    /// acceptable index was provided.
+    /// The type code of a non-valid but acceptable index, see [`Type::None`](super::Type::None).
     pub const LUA_TNONE: i32 = ffi::LUA_TNONE;
 
     /// The **nil** value type.
@@ -52,14 +58,196 @@ pub mod types {
     pub const LUA_TLIGHTUSERDATA: i32 = ffi::LUA_TLIGHTUSERDATA;
 }
 
+/// The type of a Lua value, see [`§2.1`].
+///
+/// A `Type` converts from and to the raw type codes in [`types`].
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use lua::{State, Type};
+///
+/// let mut state = State::new();
+/// state.load_string("t = {n = 1, s = 'one', b = true, t = {}}").unwrap();
+/// state.pcall(0, 0, 0).unwrap();
+///
+/// assert_eq!(state.get_global("t").unwrap(), Type::Table);
+/// for (key, expected) in [("n", Type::Number), ("s", Type::String), ("b", Type::Boolean)] {
+///     let tp = state.get_field(-1, key).unwrap();
+///     assert_eq!(tp, expected);
+///     state.pop(1);
+/// }
+/// assert_eq!(state.get_field(-1, "missing").unwrap(), Type::Nil);
+///
+/// assert_eq!(Type::from(lua::types::LUA_TSTRING), Type::String);
+/// assert_eq!(i32::from(Type::Table), lua::types::LUA_TTABLE);
+/// ```
+///
+/// [`§2.1`]: https://www.lua.org/manual/5.4/manual.html#2.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Type {
+    /// A non-valid but acceptable index.
+    None,
+    /// The **nil** value type.
+    Nil,
+    /// The *boolean* value type.
+    Boolean,
+    /// The *light user data* value type.
+    LightUserdata,
+    /// The *number* value type.
+    Number,
+    /// The *string* value type.
+    String,
+    /// The *table* value type.
+    Table,
+    /// The *function* value type.
+    Function,
+    /// The *user data* value type.
+    Userdata,
+    /// The *thread* value type.
+    Thread,
+}
+
+impl From<i32> for Type {
+    /// Converts a raw type code, any unknown code is converted to [`Type::None`].
+    fn from(code: i32) -> Self {
+        match code {
+            ffi::LUA_TNIL => Type::Nil,
+            ffi::LUA_TBOOLEAN => Type::Boolean,
+            ffi::LUA_TLIGHTUSERDATA => Type::LightUserdata,
+            ffi::LUA_TNUMBER => Type::Number,
+            ffi::LUA_TSTRING => Type::String,
+            ffi::LUA_TTABLE => Type::Table,
+            ffi::LUA_TFUNCTION => Type::Function,
+            ffi::LUA_TUSERDATA => Type::Userdata,
+            ffi::LUA_TTHREAD => Type::Thread,
+            _ => Type::None,
+        }
+    }
+}
+
+impl From<Type> for i32 {
+    fn from(tp: Type) -> Self {
+        match tp {
+            Type::None => ffi::LUA_TNONE,
+            Type::Nil => ffi::LUA_TNIL,
+            Type::Boolean => ffi::LUA_TBOOLEAN,
+            Type::LightUserdata => ffi::LUA_TLIGHTUSERDATA,
+            Type::Number => ffi::LUA_TNUMBER,
+            Type::String => ffi::LUA_TSTRING,
+            Type::Table => ffi::LUA_TTABLE,
+            Type::Function => ffi::LUA_TFUNCTION,
+            Type::Userdata => ffi::LUA_TUSERDATA,
+            Type::Thread => ffi::LUA_TTHREAD,
+        }
+    }
+}
+
+pub mod status {
+    use super::ffi;
+
+    /// No errors.
+    pub const LUA_OK: i32 = ffi::LUA_OK;
+
+    /// The thread is suspended by a yield.
+    pub const LUA_YIELD: i32 = ffi::LUA_YIELD;
+
+    /// A runtime error.
+    pub const LUA_ERRRUN: i32 = ffi::LUA_ERRRUN;
+
+    /// A syntax error during precompilation.
+    pub const LUA_ERRSYNTAX: i32 = ffi::LUA_ERRSYNTAX;
+
+    /// A memory allocation error. For such errors, Lua does not call the message handler.
+    pub const LUA_ERRMEM: i32 = ffi::LUA_ERRMEM;
+
+    /// An error while running the message handler.
+    pub const LUA_ERRERR: i32 = ffi::LUA_ERRERR;
+}
+
+pub mod consts {
+    use super::ffi;
+
+    /// The number of results requesting all the results of a call, e.g. with
+    /// [`State::pcall`](super::State::pcall).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{consts::LUA_MULTRET, State};
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return 1, 2, 3").unwrap();
+    /// state.pcall(0, LUA_MULTRET, 0).unwrap();
+    /// assert_eq!(state.top(), 3);
+    /// ```
+    pub const LUA_MULTRET: i32 = ffi::LUA_MULTRET;
+
+    /// The pseudo-index of the registry, a table to store any Lua value needed by the host.
+    pub const LUA_REGISTRYINDEX: i32 = ffi::LUA_REGISTRYINDEX;
+
+    /// The index of the main thread of the state in the registry.
+    pub const LUA_RIDX_MAINTHREAD: i64 = ffi::LUA_RIDX_MAINTHREAD;
+
+    /// The index of the global table in the registry.
+    pub const LUA_RIDX_GLOBALS: i64 = ffi::LUA_RIDX_GLOBALS;
+}
+
 pub type CFunction = unsafe extern "C" fn(*mut ffi::lua_State) -> i32;
 
+/// A continuation function, called with the status of the call and the context it was given when
+/// a yieldable call resumes (see [`State::pcallk`]).
+pub type KFunction = unsafe extern "C" fn(*mut ffi::lua_State, i32, ffi::lua_KContext) -> i32;
+
+/// A value that can be pushed onto the stack of a [`State`].
+///
+/// This is the only push trait of the crate, and [`Pull`] is its counterpart, so generic code over
+/// values crossing the Rust/Lua boundary should be bound on these two traits.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use lua::{state::Pull, State};
+///
+/// let mut state = State::new();
+/// assert_eq!(state.push(7).unwrap(), 1);
+/// assert_eq!(state.push(&b"hello"[..]).unwrap(), 1);
+/// assert_eq!(state.push((1, 2.5, true)).unwrap(), 3);
+///
+/// assert_eq!(i32::pull(&state, 1).unwrap(), 7);
+/// assert_eq!(String::pull(&state, 2).unwrap(), "hello");
+/// assert_eq!(<(i64, f64, bool)>::pull(&state, -1).unwrap(), (1, 2.5, true));
+/// ```
 pub trait Push {
     /// Pushes the value `p` onto the stack and returns the number of slots used.
     fn push(&self, state: &mut State) -> Result<i32>;
 }
 
+/// A value that can be pulled from the stack of a [`State`], the counterpart of [`Push`].
+///
+/// A value occupying several slots, like a tuple, reports it through [`size`](Pull::size) and is
+/// pulled from the index of its last slot.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use lua::{state::Pull, State};
+///
+/// let mut state = State::new();
+/// state.load_string("return 42, 'forty-two', 1, 2").unwrap();
+/// state.pcall(0, 4, 0).unwrap();
+///
+/// assert_eq!(i32::pull(&state, 1).unwrap(), 42);
+/// assert_eq!(String::pull(&state, 2).unwrap(), "forty-two");
+/// assert_eq!(<(u8, u8)>::pop(&mut state).unwrap(), (1, 2));
+/// assert_eq!(state.top(), 2);
+/// ```
 pub trait Pull {
+    /// Returns the number of stack slots used by the value.
     fn size() -> i32 {
         1
     }
@@ -78,6 +266,81 @@ pub trait Pull {
     }
 }
 
+/// A value that can be converted into Lua values pushed onto the stack, the counterpart of
+/// [`FromLua`].
+///
+/// This is implemented for every [`Push`] type, so implementing [`Push`] is enough to get it.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use std::collections::HashMap;
+///
+/// use lua::state::{FromLua, IntoLua, Pull, Push};
+/// use lua::{Error, ErrorKind, Result, State};
+///
+/// #[derive(Debug, PartialEq)]
+/// struct Point {
+///     x: f64,
+///     y: f64,
+/// }
+///
+/// impl Push for Point {
+///     fn push(&self, state: &mut State) -> Result<i32> {
+///         state.create_table(0, 2)?;
+///         state.push_number(self.x);
+///         state.set_field(-2, "x")?;
+///         state.push_number(self.y);
+///         state.set_field(-2, "y")?;
+///         Ok(1)
+///     }
+/// }
+///
+/// impl Pull for Point {
+///     fn pull(state: &State, index: i32) -> Result<Self> {
+///         let fields = HashMap::<String, f64>::pull(state, index)?;
+///         let field = |name| match fields.get(name) {
+///             Some(&value) => Ok(value),
+///             None => Err(Error::new(ErrorKind::InvalidData, "missing coordinate")),
+///         };
+///         Ok(Point { x: field("x")?, y: field("y")? })
+///     }
+/// }
+///
+/// let mut state = State::new();
+/// 7.into_lua(&mut state).unwrap();
+/// assert_eq!(i64::from_lua(&state, -1).unwrap(), 7);
+///
+/// Point { x: 1.0, y: 2.0 }.into_lua(&mut state).unwrap();
+/// assert_eq!(Point::from_lua(&state, -1).unwrap(), Point { x: 1.0, y: 2.0 });
+/// assert_eq!(state.top(), 2);
+/// ```
+pub trait IntoLua {
+    /// Pushes the value onto the stack and returns the number of slots used.
+    fn into_lua(self, state: &mut State) -> Result<i32>;
+}
+
+impl<T: Push> IntoLua for T {
+    fn into_lua(self, state: &mut State) -> Result<i32> {
+        self.push(state)
+    }
+}
+
+/// A value that can be converted from a Lua value on the stack, the counterpart of [`IntoLua`].
+///
+/// This is implemented for every [`Pull`] type, so implementing [`Pull`] is enough to get it.
+pub trait FromLua: Sized {
+    /// Converts the value at the given `index` of the stack.
+    fn from_lua(state: &State, index: i32) -> Result<Self>;
+}
+
+impl<T: Pull> FromLua for T {
+    fn from_lua(state: &State, index: i32) -> Result<Self> {
+        T::pull(state, index)
+    }
+}
+
 macro_rules! impl_primitives {
     ([$($ty:ty),*], $push:ident, $pull:ident) => {$(
         impl Push for $ty {
@@ -97,6 +360,53 @@ macro_rules! impl_primitives {
 impl_primitives!([i64, i32, i16, i8, u32, u16, u8], push_integer, to_integer);
 impl_primitives!([f64, f32], push_number, to_number);
 
+macro_rules! impl_wide_integers {
+    ($($(#[$attr:meta])* $ty:ty),*) => {$(
+        $(#[$attr])*
+        impl Push for $ty {
+            fn push(&self, state: &mut State) -> Result<i32> {
+                let n = i64::try_from(*self).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("value {} is out of the Lua integer range", self),
+                    )
+                })?;
+                state.push_integer(n);
+                Ok(1)
+            }
+        }
+        impl Pull for $ty {
+            fn pull(state: &State, index: i32) -> Result<Self> {
+                state.to_integer_strict(index)
+            }
+        }
+    )*};
+}
+
+impl_wide_integers!(
+    /// Lua integers are 64-bit, so pushing a 128-bit integer fails when the value does not fit in
+    /// an `i64`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{state::Pull, State};
+    ///
+    /// let mut state = State::new();
+    /// state.push(-7i128).unwrap();
+    /// assert_eq!(i128::pull(&state, -1).unwrap(), -7);
+    /// assert!(u128::pull(&state, -1).is_err());
+    ///
+    /// state.push(42u128).unwrap();
+    /// assert_eq!(u128::pull(&state, -1).unwrap(), 42);
+    ///
+    /// assert!(state.push(u128::MAX).is_err());
+    /// ```
+    i128,
+    u128
+);
+
 impl Push for bool {
     fn push(&self, state: &mut State) -> Result<i32> {
         state.push_boolean(*self);
@@ -113,13 +423,65 @@ impl Pull for bool {
     }
 }
 
-impl Push for &[u8] {
-    fn push(&self, state: &mut State) -> Result<i32> {
-        state.push_string(*self)?;
-        Ok(1)
-    }
+macro_rules! impl_strings {
+    ($($(#[$attr:meta])* $ty:ty),*) => {$(
+        $(#[$attr])*
+        impl Push for $ty {
+            fn push(&self, state: &mut State) -> Result<i32> {
+                state.push_string(self)?;
+                Ok(1)
+            }
+        }
+    )*};
 }
 
+impl_strings!(
+    &[u8],
+    /// Strings and byte vectors are pushed as Lua strings, like [`State::push_string`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{state::Pull, State};
+    ///
+    /// let mut state = State::new();
+    /// state.push("hi").unwrap();
+    /// assert_eq!(String::pull(&state, -1).unwrap(), "hi");
+    ///
+    /// state.push(String::from("hello")).unwrap();
+    /// assert_eq!(String::pull(&state, -1).unwrap(), "hello");
+    ///
+    /// let bytes = b"a\0b".to_vec();
+    /// state.push(&bytes).unwrap();
+    /// assert_eq!(Vec::<u8>::pull(&state, -1).unwrap(), bytes);
+    ///
+    /// state.push(bytes).unwrap();
+    /// assert_eq!(Vec::<u8>::pull(&state, -1).unwrap(), b"a\0b");
+    /// assert_eq!(state.top(), 4);
+    /// ```
+    &str,
+    String,
+    Vec<u8>,
+    &Vec<u8>
+);
+
+/// Pulls the bytes of a string as they are, without UTF-8 validation, so binary strings can be
+/// pulled.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use lua::{state::Pull, State};
+///
+/// let mut state = State::new();
+/// state.load_string(r"return 'a\xFFb'").unwrap();
+/// state.pcall(0, 1, 0).unwrap();
+///
+/// assert_eq!(Vec::<u8>::pull(&state, -1).unwrap(), b"a\xFFb");
+/// assert!(String::pull(&state, -1).is_err());
+/// ```
 impl Pull for Vec<u8> {
     fn pull(state: &State, index: i32) -> Result<Self>
     where
@@ -140,98 +502,545 @@ impl Pull for String {
     }
 }
 
-macro_rules! impl_tuples {
-    ($len:tt, $($idx:tt $T:ident)+) => {
-        impl<$($T: Push),+> Push for ($($T,)+) {
-            fn push(&self, state: &mut State) -> Result<i32> {
-                let mut n = 0;
-                $(n += self.$idx.push(&mut *state)?;)+
-                Ok(n)
+/// Pushes **nil** for `None`, otherwise pushes the contained value.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use lua::{state::Pull, State};
+///
+/// let mut state = State::new();
+/// state.push(None::<i32>).unwrap();
+/// assert!(state.is_nil(-1));
+/// assert_eq!(Option::<i32>::pull(&state, -1).unwrap(), None);
+///
+/// state.push(Some(3)).unwrap();
+/// assert_eq!(Option::<i32>::pull(&state, -1).unwrap(), Some(3));
+/// ```
+impl<T: Push> Push for Option<T> {
+    fn push(&self, state: &mut State) -> Result<i32> {
+        match self {
+            Some(t) => t.push(state),
+            None => {
+                state.push_nil();
+                Ok(1)
             }
         }
+    }
+}
 
-        impl<$($T: Pull),+> Pull for ($($T,)+) {
-            fn size() -> i32 {
-                0 $(+ $T::size())*
-            }
+/// Pulls `None` for **nil** or a non-valid index, otherwise pulls the value as `T`.
+impl<T: Pull> Pull for Option<T> {
+    fn size() -> i32 {
+        T::size()
+    }
 
-            fn pull(state: &State, index: i32) -> Result<Self>
-            where
-                    Self: Sized {
-                Ok(($($T::pull(&*state, index - ($len - 1) + $idx)?,)+))
-            }
+    fn pull(state: &State, index: i32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        if state.is_none_or_nil(index) {
+            Ok(None)
+        } else {
+            T::pull(state, index).map(Some)
         }
-    };
+    }
 }
 
-impl_tuples! { 1, 0 A }
-impl_tuples! { 2, 0 A 1 B }
-impl_tuples! { 3, 0 A 1 B 2 C}
-impl_tuples! { 4, 0 A 1 B 2 C 3 D}
-impl_tuples! { 5, 0 A 1 B 2 C 3 D 4 E}
-impl_tuples! { 6, 0 A 1 B 2 C 3 D 4 E 5 F}
-impl_tuples! { 7, 0 A 1 B 2 C 3 D 4 E 5 F 6 G}
-impl_tuples! { 8, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H}
-impl_tuples! { 9, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I}
-impl_tuples! { 10, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J}
-impl_tuples! { 11, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J 10 K}
-impl_tuples! { 12, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J 10 K 11 L}
-
-/// A Lua state.
+/// Pulls all the key-value pairs of a table, in traversal order, including non-string keys.
+///
+/// Fails when the value is not a table or when any key or value cannot be pulled as `K` or `V`.
 ///
 /// # Examples
 ///
 /// ```
 /// # extern crate lua;
-/// use lua::State;
+/// use lua::{state::Pull, State};
 ///
-/// let state = State::default();
+/// let mut state = State::new();
+/// state.load_string("return {10, 20, x = 30}, {[true] = 1}").unwrap();
+/// state.pcall(0, 2, 0).unwrap();
+///
+/// let mut pairs = Vec::<(String, i64)>::pull(&state, -2).unwrap();
+/// pairs.sort();
+/// assert_eq!(pairs, vec![("1".into(), 10), ("2".into(), 20), ("x".into(), 30)]);
+///
+/// assert_eq!(Vec::<(String, i64)>::pull(&state, 1).unwrap().len(), 3);
+/// assert_eq!(state.top(), 2);
+///
+/// assert!(Vec::<(i64, i64)>::pull(&state, -1).is_err());
+/// assert_eq!(state.top(), 2);
 /// ```
-pub struct State {
-    ptr: NonNull<ffi::lua_State>,
-    droppable: bool,
-}
-
-unsafe impl Send for State {}
+impl<K: Pull, V: Pull> Pull for Vec<(K, V)> {
+    fn pull(state: &State, index: i32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        // the view is a non-owning alias of the same Lua state, the guard restores its top on
+        // every return path, including errors
+        let mut state = state.alias();
+        let mut state = StackGuard::new(&mut state);
+        let index = state.abs_index(index);
+        if !state.is_table(index) {
+            return Err(Error::new(ErrorKind::InvalidData, "value is not a table"));
+        }
 
-impl fmt::Debug for State {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "State({:p})", self.ptr)
+        let mut pairs: Vec<(K, V)> = Vec::new();
+        state.push_nil();
+        while state.next(index) {
+            // pull the key from a copy, converting it in place would confuse `lua_next`
+            state.push_value(-2);
+            let key = K::pull(&state, -1)?;
+            let value = V::pull(&state, -2)?;
+            pairs.push((key, value));
+            state.pop(2);
+        }
+        Ok(pairs)
     }
 }
 
-impl fmt::Pointer for State {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Pointer::fmt(&self.ptr, f)
+/// Pushes the `key` and the `value` of a map entry, failing when either does not use exactly one
+/// slot or when the key is **nil** or NaN, which cannot be table keys.
+fn push_entry<K: Push, V: Push>(state: &mut State, key: &K, value: &V) -> Result<()> {
+    if key.push(state)? != 1 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "map key must use one slot",
+        ));
     }
+    if state.is_nil(-1) || matches!(state.to_number::<f64>(-1), Some(n) if n.is_nan()) {
+        return Err(Error::new(ErrorKind::InvalidInput, "map key is nil or NaN"));
+    }
+    if value.push(state)? != 1 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "map value must use one slot",
+        ));
+    }
+    Ok(())
 }
 
-impl State {
-    /// Creates a new `State` without a memory limit.
-    ///
-    /// # Panics
-    ///
-    /// Panics when the ptr is non-null.
+macro_rules! impl_maps {
+    ($($(#[$attr:meta])* $map:ident<K: $($bound:ident)+>),*) => {$(
+        $(#[$attr])*
+        impl<K: Push, V: Push> Push for $map<K, V> {
+            fn push(&self, state: &mut State) -> Result<i32> {
+                let mark = state.top();
+                state.create_table(0, self.len() as i32)?;
+                for (key, value) in self {
+                    if let Err(e) = push_entry(state, key, value) {
+                        state.set_top(mark);
+                        return Err(e);
+                    }
+                    state.raw_set(-3);
+                }
+                Ok(1)
+            }
+        }
+
+        impl<K: Pull $(+ $bound)+, V: Pull> Pull for $map<K, V> {
+            fn pull(state: &State, index: i32) -> Result<Self>
+            where
+                Self: Sized,
+            {
+                Vec::<(K, V)>::pull(state, index).map(|pairs| pairs.into_iter().collect())
+            }
+        }
+    )*};
+}
+
+impl_maps!(
+    /// Pushes a new table holding all the key-value pairs of the map; the map is pulled back from
+    /// all the key-value pairs of a table, as for `Vec<(K, V)>`.
     ///
     /// # Examples
     ///
     /// ```
     /// # extern crate lua;
-    /// use lua::State;
+    /// use std::collections::HashMap;
     ///
-    /// let state = State::new();
-    /// ```
-    pub fn new() -> Self {
-        Self::with_limit(0)
-    }
-
-    /// Constructs a new `State` with the specified memory limit.
+    /// use lua::{state::Pull, State};
     ///
-    /// # Panics
+    /// let mut state = State::new();
+    /// let mut map = HashMap::new();
+    /// map.insert(&b"one"[..], 1);
+    /// map.insert(&b"two"[..], 2);
+    /// assert_eq!(state.push(map).unwrap(), 1);
     ///
-    /// Panics when the ptr is non-null.
+    /// let map = HashMap::<String, i32>::pull(&state, -1).unwrap();
+    /// assert_eq!(map.len(), 2);
+    /// assert_eq!(map["one"], 1);
+    /// assert_eq!(map["two"], 2);
     ///
-    /// # Examples
+    /// // nil cannot be a table key, a key must use a single slot
+    /// let mut map = HashMap::new();
+    /// map.insert(None::<i64>, 1);
+    /// assert!(state.push(map).is_err());
+    /// let mut map = HashMap::new();
+    /// map.insert((1, 2), 1);
+    /// assert!(state.push(map).is_err());
+    /// assert_eq!(state.top(), 1);
+    /// ```
+    HashMap<K: Eq Hash>,
+    /// Pushes a new table holding all the key-value pairs of the map; the map is pulled back from
+    /// all the key-value pairs of a table, as for `Vec<(K, V)>`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use std::collections::BTreeMap;
+    ///
+    /// use lua::{state::Pull, State};
+    ///
+    /// let mut state = State::new();
+    /// let mut map = BTreeMap::new();
+    /// map.insert(1, &b"a"[..]);
+    /// map.insert(2, b"b");
+    /// map.insert(10, b"c");
+    /// state.push(map).unwrap();
+    ///
+    /// let map = BTreeMap::<i64, String>::pull(&state, -1).unwrap();
+    /// let expected: BTreeMap<i64, String> =
+    ///     vec![(1, "a".into()), (2, "b".into()), (10, "c".into())].into_iter().collect();
+    /// assert_eq!(map, expected);
+    /// ```
+    BTreeMap<K: Ord>
+);
+
+/// Key-value pairs kept in order, pushed as a sequence of `{key, value}` tables rather than as a
+/// table mapping the keys to the values, whose traversal order is unspecified.
+///
+/// This is meant for maps which must round-trip in a deterministic order, e.g. for snapshot tests.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use std::collections::BTreeMap;
+///
+/// use lua::state::{Pairs, Pull};
+/// use lua::State;
+///
+/// let mut map = BTreeMap::new();
+/// map.insert("b", 2);
+/// map.insert("a", 1);
+/// map.insert("c", 3);
+///
+/// let mut state = State::new();
+/// state.push(map.iter().map(|(k, v)| (k.as_bytes(), *v)).collect::<Pairs<_, _>>()).unwrap();
+/// assert_eq!(state.raw_len(-1), 3);
+/// state.raw_get_index(-1, 1);
+/// state.raw_get_index(-1, 1);
+/// assert_eq!(state.as_bytes(-1), b"a");
+/// state.pop(2);
+///
+/// let Pairs(pairs) = Pairs::<String, i64>::pull(&state, -1).unwrap();
+/// assert_eq!(pairs, vec![("a".into(), 1), ("b".into(), 2), ("c".into(), 3)]);
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pairs<K, V>(pub Vec<(K, V)>);
+
+impl<K, V> FromIterator<(K, V)> for Pairs<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Pairs(iter.into_iter().collect())
+    }
+}
+
+impl<K: Push, V: Push> Push for Pairs<K, V> {
+    fn push(&self, state: &mut State) -> Result<i32> {
+        let mark = state.top();
+        let ret = state.create_table(self.0.len() as i32, 0).and_then(|_| {
+            for (i, (key, value)) in self.0.iter().enumerate() {
+                state.create_table(2, 0)?;
+                key.push(state)?;
+                state.raw_set_index(-2, 1);
+                value.push(state)?;
+                state.raw_set_index(-2, 2);
+                state.raw_set_index(-2, i as i64 + 1);
+            }
+            Ok(1)
+        });
+        if ret.is_err() {
+            state.set_top(mark);
+        }
+        ret
+    }
+}
+
+/// Pulls the `{key, value}` tables of a sequence, failing when an element is not such a table or
+/// when its key or value cannot be pulled as `K` or `V`.
+impl<K: Pull, V: Pull> Pull for Pairs<K, V> {
+    fn pull(state: &State, index: i32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        // the view is a non-owning alias of the same Lua state, the guard restores its top on
+        // every return path, including errors
+        let mut state = state.alias();
+        let mut state = StackGuard::new(&mut state);
+        let index = state.abs_index(index);
+        if !state.is_table(index) {
+            return Err(Error::new(ErrorKind::InvalidData, "value is not a table"));
+        }
+
+        let len = state.raw_len(index);
+        let mut pairs: Vec<(K, V)> = Vec::with_capacity(len);
+        for i in 1..=len {
+            if state.raw_get_index(index, i as i64) != Type::Table {
+                let msg = format!("element {} is not a key-value pair", i);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            state.raw_get_index(-1, 1);
+            state.raw_get_index(-2, 2);
+            let key = K::pull(&state, -2)?;
+            let value = V::pull(&state, -1)?;
+            pairs.push((key, value));
+            state.pop(3);
+        }
+        Ok(Pairs(pairs))
+    }
+}
+
+macro_rules! impl_tuples {
+    ($len:tt, $($idx:tt $T:ident)+) => {
+        impl<$($T: Push),+> Push for ($($T,)+) {
+            fn push(&self, state: &mut State) -> Result<i32> {
+                let mut n = 0;
+                $(n += self.$idx.push(&mut *state)?;)+
+                Ok(n)
+            }
+        }
+
+        impl<$($T: Pull),+> Pull for ($($T,)+) {
+            fn size() -> i32 {
+                0 $(+ $T::size())*
+            }
+
+            fn pull(state: &State, index: i32) -> Result<Self>
+            where
+                    Self: Sized {
+                Ok(($($T::pull(&*state, index - ($len - 1) + $idx)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuples! { 1, 0 A }
+impl_tuples! { 2, 0 A 1 B }
+impl_tuples! { 3, 0 A 1 B 2 C}
+impl_tuples! { 4, 0 A 1 B 2 C 3 D}
+impl_tuples! { 5, 0 A 1 B 2 C 3 D 4 E}
+impl_tuples! { 6, 0 A 1 B 2 C 3 D 4 E 5 F}
+impl_tuples! { 7, 0 A 1 B 2 C 3 D 4 E 5 F 6 G}
+impl_tuples! { 8, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H}
+impl_tuples! { 9, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I}
+impl_tuples! { 10, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J}
+impl_tuples! { 11, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J 10 K}
+impl_tuples! { 12, 0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J 10 K 11 L}
+
+/// The empty tuple uses no slot, e.g. to call a function without arguments or to discard its
+/// results.
+impl Push for () {
+    fn push(&self, _state: &mut State) -> Result<i32> {
+        Ok(0)
+    }
+}
+
+impl Pull for () {
+    fn size() -> i32 {
+        0
+    }
+
+    fn pull(_state: &State, _index: i32) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(())
+    }
+}
+
+/// The kind of chunks accepted when loading a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Mode {
+    /// Only text chunks.
+    Text,
+    /// Only binary chunks, that is precompiled chunks.
+    Binary,
+    /// Both text and binary chunks.
+    Both,
+}
+
+impl Mode {
+    fn as_c_str(&self) -> &'static CStr {
+        let mode: &'static [u8] = match *self {
+            Mode::Text => b"t\0",
+            Mode::Binary => b"b\0",
+            Mode::Both => b"bt\0",
+        };
+        // SAFETY: This unsafety is ok because all modes are nul-terminated without interior nul.
+        unsafe { CStr::from_bytes_with_nul_unchecked(mode) }
+    }
+}
+
+/// A standard Lua library, see [`§6`].
+///
+/// [`§6`]: https://www.lua.org/manual/5.4/manual.html#6
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StdLib {
+    /// The basic library, opened as the global table `_G`.
+    Base,
+    /// The package library.
+    Package,
+    /// The coroutine library.
+    Coroutine,
+    /// The table manipulation library.
+    Table,
+    /// The input and output library.
+    Io,
+    /// The operating system facilities library.
+    Os,
+    /// The string manipulation library.
+    String,
+    /// The mathematical library.
+    Math,
+    /// The UTF-8 support library.
+    Utf8,
+    /// The debug library.
+    Debug,
+}
+
+impl StdLib {
+    fn name(&self) -> &'static CStr {
+        let name: &'static [u8] = match *self {
+            StdLib::Base => b"_G\0",
+            StdLib::Package => b"package\0",
+            StdLib::Coroutine => b"coroutine\0",
+            StdLib::Table => b"table\0",
+            StdLib::Io => b"io\0",
+            StdLib::Os => b"os\0",
+            StdLib::String => b"string\0",
+            StdLib::Math => b"math\0",
+            StdLib::Utf8 => b"utf8\0",
+            StdLib::Debug => b"debug\0",
+        };
+        // SAFETY: This unsafety is ok because all names are nul-terminated without interior nul.
+        unsafe { CStr::from_bytes_with_nul_unchecked(name) }
+    }
+
+    fn open_function(&self) -> CFunction {
+        match *self {
+            StdLib::Base => ffi::luaopen_base,
+            StdLib::Package => ffi::luaopen_package,
+            StdLib::Coroutine => ffi::luaopen_coroutine,
+            StdLib::Table => ffi::luaopen_table,
+            StdLib::Io => ffi::luaopen_io,
+            StdLib::Os => ffi::luaopen_os,
+            StdLib::String => ffi::luaopen_string,
+            StdLib::Math => ffi::luaopen_math,
+            StdLib::Utf8 => ffi::luaopen_utf8,
+            StdLib::Debug => ffi::luaopen_debug,
+        }
+    }
+}
+
+/// A mode of the garbage collector, with its parameters, see [`§2.5`].
+///
+/// A parameter set to 0 keeps its current value.
+///
+/// [`§2.5`]: https://www.lua.org/manual/5.4/manual.html#2.5
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GcMode {
+    /// The incremental mode, where each cycle does a mark-and-sweep collection in small steps
+    /// interleaved with the program.
+    Incremental {
+        /// How long the collector waits before starting a new cycle, in percent of the memory in
+        /// use after the previous collection.
+        pause: i32,
+        /// The speed of the collector relative to memory allocation, in percent.
+        stepmul: i32,
+        /// The size of each step, as the base-2 logarithm of the number of bytes.
+        stepsize: i32,
+    },
+    /// The generational mode, where the collector does frequent minor collections traversing
+    /// only recently created objects.
+    Generational {
+        /// The frequency of minor collections, in percent of the memory growth since the
+        /// previous major collection.
+        minormul: i32,
+        /// The threshold for major collections, in percent of the memory growth since the
+        /// previous major collection.
+        majormul: i32,
+    },
+}
+
+/// A Lua state.
+///
+/// Only the `State` that created the underlying Lua state closes it when dropped. A `State` is
+/// not `Clone`, as a Lua state cannot be copied; [`.view()`] returns a non-owning alias of the same
+/// Lua state instead, which mutably borrows the `State` it was created from.
+///
+/// # Thread safety
+///
+/// A `State` can be moved to another thread, but a Lua state is not thread-safe: a `State` and
+/// its views must never be used concurrently from several threads.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use lua::State;
+///
+/// let state = State::default();
+/// ```
+///
+/// [`.view()`]: State::view
+pub struct State {
+    ptr: NonNull<ffi::lua_State>,
+    droppable: bool,
+}
+
+unsafe impl Send for State {}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "State({:p})", self.ptr)
+    }
+}
+
+impl fmt::Pointer for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&self.ptr, f)
+    }
+}
+
+impl State {
+    /// Creates a new `State` without a memory limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics when the ptr is non-null.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let state = State::new();
+    /// ```
+    pub fn new() -> Self {
+        Self::with_limit(0)
+    }
+
+    /// Constructs a new `State` with the specified memory limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics when the ptr is non-null.
+    ///
+    /// # Examples
     ///
     /// ```
     /// # extern crate lua;
@@ -250,8 +1059,102 @@ impl State {
         Self::from_ptr(ptr, true)
     }
 
+    /// Constructs a new `State` allocating its memory with `allocator`, which lives as long as
+    /// the state. No memory limit applies to such a state.
+    ///
+    /// # Panics
+    ///
+    /// Panics when the Lua state cannot be allocated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use std::alloc::{self, Layout};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// use lua::{LuaAllocator, State};
+    ///
+    /// struct Counting(Arc<AtomicUsize>);
+    ///
+    /// fn layout(size: usize) -> Layout {
+    ///     Layout::from_size_align(size, 16).unwrap()
+    /// }
+    ///
+    /// unsafe impl LuaAllocator for Counting {
+    ///     fn alloc(&mut self, size: usize) -> *mut u8 {
+    ///         self.0.fetch_add(1, Ordering::SeqCst);
+    ///         unsafe { alloc::alloc(layout(size)) }
+    ///     }
+    ///
+    ///     fn realloc(&mut self, ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+    ///         unsafe { alloc::realloc(ptr, layout(old_size), new_size) }
+    ///     }
+    ///
+    ///     fn free(&mut self, ptr: *mut u8, size: usize) {
+    ///         unsafe { alloc::dealloc(ptr, layout(size)) }
+    ///     }
+    /// }
+    ///
+    /// let allocations = Arc::new(AtomicUsize::new(0));
+    /// let mut state = State::with_allocator(Counting(Arc::clone(&allocations)));
+    ///
+    /// let before = allocations.load(Ordering::SeqCst);
+    /// state.new_table().unwrap();
+    /// assert!(allocations.load(Ordering::SeqCst) > before);
+    ///
+    /// // the allocator is dropped with the state
+    /// drop(state);
+    /// assert_eq!(Arc::strong_count(&allocations), 1);
+    /// ```
+    pub fn with_allocator<A: LuaAllocator + Send + 'static>(allocator: A) -> Self {
+        let allocator: Box<dyn LuaAllocator + Send> = Box::new(allocator);
+        let ud = Box::into_raw(Box::new(allocator));
+
+        let ptr = unsafe { ffi::lua_newstate(alloc::alloc_with, ud as _) };
+        debug!("{:p} new state with allocator", ptr);
+
+        Self::from_ptr(ptr, true)
+    }
+
+    /// Returns a non-owning view on the same Lua state, which does not close the Lua state when
+    /// dropped and cannot outlive this `State` (see [`StateView`]).
+    ///
+    /// The view is an alias rather than an independent state: both share the same stack, globals
+    /// and registry, so values pushed through one are seen by the other.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// let mut view = state.view();
+    /// view.push_integer(42);
+    ///
+    /// // dropping the view leaves the Lua state open
+    /// drop(view);
+    /// assert_eq!(state.top(), 1);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(42));
+    /// ```
+    pub fn view(&mut self) -> StateView<'_> {
+        self.alias()
+    }
+
+    /// Returns a view on this state from a shared reference, for the conversions of [`Pull`] which
+    /// push temporary values: they must restore the top of the stack before returning and never
+    /// remove or replace the values below it.
+    fn alias(&self) -> StateView<'_> {
+        StateView {
+            state: Self::from_ptr(self.as_ptr(), false),
+            _parent: PhantomData,
+        }
+    }
+
     /// Gets a mutable pointer to the Lua state pointer.
-    fn as_ptr(&self) -> *mut ffi::lua_State {
+    pub(crate) fn as_ptr(&self) -> *mut ffi::lua_State {
         self.ptr.as_ptr()
     }
 
@@ -274,6 +1177,22 @@ impl State {
         Self { ptr, droppable }
     }
 
+    /// Returns the version number of the Lua core this state runs on.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let state = State::new();
+    /// assert_eq!(state.version(), 504.0);
+    /// assert_eq!(state.version(), f64::from(lua::LUA_VERSION_NUM));
+    /// ```
+    pub fn version(&self) -> f64 {
+        unsafe { ffi::lua_version(self.as_ptr()) }
+    }
+
     /// Opens all standard Lua libraries into the given state.
     ///
     /// # Examples
@@ -289,45 +1208,524 @@ impl State {
         unsafe { ffi::luaL_openlibs(self.as_ptr()) }
     }
 
-    /// Loads a string as a Lua chunk. This function uses [`.load()`] to load the chunk in the
-    /// provided data.
+    /// Opens the given standard Lua library into the given state, setting it into
+    /// `package.loaded` and into a global variable, so a sandbox can open a curated subset of the
+    /// standard libraries instead of all of them with [`.open_libs()`].
     ///
-    /// This function returns the same results as [`.load()`].
+    /// # Examples
     ///
-    /// Also as [`.load()`], this function only loads the chunk; it does not run it.
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::StdLib;
+    /// use lua::State;
     ///
-    /// [`.load()`]: State::load
-    pub fn load_string<T: Into<Vec<u8>>>(&mut self, t: T) -> Result<()> {
-        let s = CString::new(t)?;
-
-        // load the zero terminated C string
-        let code = unsafe { ffi::luaL_loadstring(self.as_ptr(), s.as_ptr()) };
-        self.handle_result(code, ())
-    }
-
-    /// Calls a function (or a callable object) in protected mode.
+    /// let mut state = State::new();
+    /// state.open_lib(StdLib::Base);
+    /// state.open_lib(StdLib::Math);
     ///
-    /// Always removes the function and its arguments from the stack.
-    pub fn pcall(&mut self, nargs: i32, nresults: i32, msgh: i32) -> Result<()> {
-        let code = unsafe { ffi::lua_pcall(self.as_ptr(), nargs, nresults, msgh) };
-        self.handle_result(code, ())
+    /// state.load_string("return math.sqrt(16)").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    /// assert_eq!(state.to_number::<f64>(-1), Some(4.0));
+    ///
+    /// state.load_string("return os.time()").unwrap();
+    /// assert!(state.pcall(0, 1, 0).is_err());
+    /// ```
+    ///
+    /// [`.open_libs()`]: #method.open_libs
+    pub fn open_lib(&mut self, lib: StdLib) {
+        unsafe {
+            ffi::luaL_requiref(self.as_ptr(), lib.name().as_ptr(), lib.open_function(), 1);
+            ffi::lua_pop(self.as_ptr(), 1);
+        }
     }
 
-    /// Returns a [`Result<T>`](crate::error::Result) based on provided result `code`.
+    /// Returns the names of the modules already loaded, that is the keys of `package.loaded`.
     ///
-    /// When `code` is not `LUA_OK` or `LUA_YIELD`, it will read the error code from the top of the
-    /// stack and return an [`Err`], otherwise [`Ok`] is returned with the provided `value`.
-    fn handle_result<T>(&self, code: i32, value: T) -> Result<T> {
-        match code {
+    /// This includes the standard libraries opened into the state.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.load_string(r#"
+    ///     package.preload.foo = function() return {} end
+    ///     package.preload.bar = function() return {} end
+    ///     require "foo"
+    ///     require "bar"
+    /// "#).unwrap();
+    /// state.pcall(0, 0, 0).unwrap();
+    ///
+    /// let modules = state.loaded_modules().unwrap();
+    /// assert!(modules.contains(&"foo".to_string()));
+    /// assert!(modules.contains(&"bar".to_string()));
+    /// assert!(modules.contains(&"string".to_string()));
+    /// ```
+    pub fn loaded_modules(&mut self) -> Result<Vec<String>> {
+        let mut state = StackGuard::new(self);
+        let mut names = Vec::new();
+        if state.get_field(ffi::LUA_REGISTRYINDEX, ffi::LUA_LOADED_TABLE)? != Type::Table {
+            return Ok(names);
+        }
+
+        state.push_nil();
+        while state.next(-2) {
+            state.pop(1);
+            if state.info(-1).value_type() == Type::String {
+                names.push(String::pull(&state, -1)?);
+            }
+        }
+        Ok(names)
+    }
+
+    /// Registers `loader` as the loader of the module `name` in `package.preload`, so that Lua
+    /// code calling `require(name)` calls the loader, whose first result becomes the module.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn answer(ptr: *mut ffi::lua_State) -> i32 {
+    ///     State::from_ptr(ptr, false).push_integer(42);
+    ///     1
+    /// }
+    ///
+    /// unsafe extern "C" fn open_answers(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     state.new_table().unwrap();
+    ///     state.push_cfunction(answer);
+    ///     state.set_field(-2, "answer").unwrap();
+    ///     1
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.preload_module("answers", open_answers).unwrap();
+    ///
+    /// state.load_string("return require('answers').answer()").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(42));
+    /// ```
+    pub fn preload_module<T: Into<Vec<u8>>>(&mut self, name: T, loader: CFunction) -> Result<()> {
+        let mut state = StackGuard::new(self);
+        state.get_subtable(ffi::LUA_REGISTRYINDEX, ffi::LUA_PRELOAD_TABLE)?;
+        state.push_cfunction(loader);
+        state.set_field(-2, name)
+    }
+
+    /// Loads a string as a Lua chunk. This function uses [`.load()`] to load the chunk in the
+    /// provided data.
+    ///
+    /// This function returns the same results as [`.load()`].
+    ///
+    /// Also as [`.load()`], this function only loads the chunk; it does not run it.
+    ///
+    /// [`.load()`]: State::load
+    pub fn load_string<T: Into<Vec<u8>>>(&mut self, t: T) -> Result<()> {
+        let s = CString::new(t)?;
+
+        // load the zero terminated C string
+        let code = unsafe { ffi::luaL_loadstring(self.as_ptr(), s.as_ptr()) };
+        self.handle_result(code, ())
+    }
+
+    /// Loads a buffer as a Lua chunk, without running it.
+    ///
+    /// The `name` is the chunk name, used for error messages and in debug information (see
+    /// [`§4.7`]). A name starting with `@` is a file name and a name starting with `=` is shown
+    /// as is, any other name is shown as the source itself, e.g. `[string "name"]`. The `mode`
+    /// controls whether the chunk can be text or binary (that is, a precompiled chunk).
+    ///
+    /// The bytes are handed to Lua as is, so unlike [`.load_string()`] they may contain nul bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::{Mode, Pull};
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.load_bytes(b"return 1 + 2", "=sum", Mode::Text).unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(3));
+    ///
+    /// let error = state.load_bytes(b"error('boom')", "@boom.lua", Mode::Text)
+    ///     .and_then(|_| state.pcall(0, 0, 0))
+    ///     .unwrap_err();
+    /// assert!(error.to_string().contains("boom.lua:1: boom"));
+    ///
+    /// let error = state.load_bytes(b"error('boom')", "=boom", Mode::Text)
+    ///     .and_then(|_| state.pcall(0, 0, 0))
+    ///     .unwrap_err();
+    /// assert!(error.to_string().contains("boom:1: boom"));
+    /// state.set_top(0);
+    ///
+    /// state.load_string("return string.dump(function(n) return n * 2 end)").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    /// let chunk: Vec<u8> = Pull::pop(&mut state).unwrap();
+    ///
+    /// assert!(state.load_bytes(&chunk, "=double", Mode::Text).is_err());
+    /// state.pop(1);
+    ///
+    /// state.load_bytes(&chunk, "=double", Mode::Binary).unwrap();
+    /// state.push_integer(21);
+    /// state.pcall(1, 1, 0).unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(42));
+    /// ```
+    ///
+    /// [`§4.7`]: https://www.lua.org/manual/5.4/manual.html#4.7
+    /// [`.load_string()`]: State::load_string
+    pub fn load_bytes(&mut self, bytes: &[u8], name: &str, mode: Mode) -> Result<()> {
+        let name = CString::new(name)?;
+        let code = unsafe {
+            ffi::luaL_loadbufferx(
+                self.as_ptr(),
+                bytes.as_ptr() as *const i8,
+                bytes.len(),
+                name.as_ptr(),
+                mode.as_c_str().as_ptr(),
+            )
+        };
+        self.handle_result(code, ())
+    }
+
+    /// Loads the file at the given `path` as a Lua chunk, text or binary, without running it.
+    ///
+    /// The chunk name is the path prefixed with `@`, so error messages and tracebacks show the
+    /// path of the file. A file that cannot be read, for instance because it does not exist, fails
+    /// with the underlying I/O error. As with `luaL_loadfile`, a first line starting with `#` is
+    /// skipped, so executable scripts can start with a `#!` line.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.load_file("examples/call.lua").unwrap();
+    /// state.pcall(0, 0, 0).unwrap();
+    ///
+    /// state.get_global("f").unwrap();
+    /// state.push_number(2.0);
+    /// state.push_number(0.0);
+    /// state.pcall(2, 2, 0).unwrap();
+    /// assert_eq!(state.as_bytes(-1), b"foo bar");
+    ///
+    /// assert!(state.load_file("examples/missing.lua").is_err());
+    ///
+    /// let path = std::env::temp_dir().join("lua-load-file-shebang.lua");
+    /// std::fs::write(&path, "#!/usr/bin/env lua\nerror('boom')\n").unwrap();
+    /// state.load_file(&path).unwrap();
+    /// let error = state.pcall(0, 0, 0).unwrap_err();
+    /// assert!(error.to_string().contains(".lua:2: boom"));
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        let name = format!("@{}", path.display());
+
+        // skip a first line starting with `#`, as in Unix executable scripts, keeping its newline
+        // so that line numbers are unchanged, unless a precompiled chunk follows
+        let mut chunk = &bytes[..];
+        if chunk.starts_with(b"#") {
+            let end = chunk
+                .iter()
+                .position(|&b| b == b'\n')
+                .unwrap_or(chunk.len());
+            chunk = &chunk[end..];
+            if chunk.starts_with(b"\n\x1bLua") {
+                chunk = &chunk[1..];
+            }
+        }
+        self.load_bytes(chunk, &name, Mode::Both)
+    }
+
+    /// Loads and runs the given string, like `luaL_dostring`. The results of the chunk are left on
+    /// the stack.
+    ///
+    /// When the chunk fails to load the error has the `LUA_ERRSYNTAX` (or `LUA_ERRMEM`) Lua code,
+    /// when it fails to run the error has the `LUA_ERRRUN` Lua code, see [`Error::lua_code`]. In
+    /// both cases the error message is left on the stack.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::status::{LUA_ERRRUN, LUA_ERRSYNTAX};
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.do_string("return 1, 2").unwrap();
+    /// assert_eq!(state.top(), 2);
+    /// state.set_top(0);
+    ///
+    /// let error = state.do_string("x = = 1").unwrap_err();
+    /// assert_eq!(error.lua_code(), Some(LUA_ERRSYNTAX));
+    /// state.pop(1);
+    ///
+    /// let error = state.do_string("error('boom')").unwrap_err();
+    /// assert_eq!(error.lua_code(), Some(LUA_ERRRUN));
+    /// assert!(error.to_string().contains("boom"));
+    /// ```
+    ///
+    /// [`Error::lua_code`]: crate::Error::lua_code
+    pub fn do_string<T: Into<Vec<u8>>>(&mut self, t: T) -> Result<()> {
+        self.load_string(t)?;
+        self.pcall(0, ffi::LUA_MULTRET, 0)
+    }
+
+    /// Loads and runs the file at the given `path`, like `luaL_dofile`. The results of the chunk
+    /// are left on the stack.
+    ///
+    /// Fails as [`.load_file()`] when the file cannot be read or loaded, otherwise fails as
+    /// [`.do_string()`] when the chunk fails to run.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.do_file("examples/call.lua").unwrap();
+    /// state.get_global("f").unwrap();
+    /// assert!(state.is_function(-1));
+    /// ```
+    ///
+    /// [`.load_file()`]: #method.load_file
+    /// [`.do_string()`]: #method.do_string
+    pub fn do_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.load_file(path)?;
+        self.pcall(0, ffi::LUA_MULTRET, 0)
+    }
+
+    /// Calls a function (or a callable object) in protected mode.
+    ///
+    /// Always removes the function and its arguments from the stack. When the call fails, the
+    /// error message is left on the stack and the returned error has the [`InvalidData`] kind,
+    /// the Lua status code (see [`Error::lua_code`]) and the original Lua message.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::status::LUA_ERRRUN;
+    /// use lua::{ErrorKind, State};
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("error('something went wrong')").unwrap();
+    ///
+    /// let error = state.pcall(0, 0, 0).unwrap_err();
+    /// assert_eq!(error.kind(), ErrorKind::InvalidData);
+    /// assert_eq!(error.lua_code(), Some(LUA_ERRRUN));
+    /// assert!(error.to_string().contains("something went wrong"));
+    /// assert_eq!(state.top(), 1);
+    /// ```
+    ///
+    /// [`InvalidData`]: crate::ErrorKind::InvalidData
+    /// [`Error::lua_code`]: crate::Error::lua_code
+    pub fn pcall(&mut self, nargs: i32, nresults: i32, msgh: i32) -> Result<()> {
+        let code = unsafe { ffi::lua_pcall(self.as_ptr(), nargs, nresults, msgh) };
+        self.handle_result(code, ())
+    }
+
+    /// Calls a function in protected mode like [`.pcall()`], but returns the bare status code on
+    /// error, such as [`LUA_ERRRUN`], and leaves the error object untouched on the top of the
+    /// stack.
+    ///
+    /// Lua errors can be any value, so this lets the caller inspect structured errors, such as
+    /// tables, which [`.pcall()`] only describes by their string conversion.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::status::LUA_ERRRUN;
+    /// use lua::state::Pull;
+    /// use lua::{Result, State};
+    ///
+    /// #[derive(Debug, PartialEq)]
+    /// struct AppError {
+    ///     code: i64,
+    ///     message: String,
+    /// }
+    ///
+    /// fn pop_app_error(state: &mut State) -> Result<AppError> {
+    ///     state.get_field(-1, "code")?;
+    ///     state.get_field(-2, "message")?;
+    ///     let error = AppError {
+    ///         code: state.to_integer(-2).unwrap_or_default(),
+    ///         message: String::pull(state, -1)?,
+    ///     };
+    ///     state.pop(3);
+    ///     Ok(error)
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.load_string("error({ code = 404, message = 'not found' })").unwrap();
+    ///
+    /// assert_eq!(state.pcall_raw(0, 0, 0), Err(LUA_ERRRUN));
+    /// assert!(state.is_table(-1));
+    ///
+    /// let error = pop_app_error(&mut state).unwrap();
+    /// assert_eq!(error, AppError { code: 404, message: "not found".to_string() });
+    /// assert_eq!(state.top(), 0);
+    /// ```
+    ///
+    /// [`.pcall()`]: State::pcall
+    /// [`LUA_ERRRUN`]: status::LUA_ERRRUN
+    pub fn pcall_raw(
+        &mut self,
+        nargs: i32,
+        nresults: i32,
+        msgh: i32,
+    ) -> std::result::Result<(), i32> {
+        match unsafe { ffi::lua_pcall(self.as_ptr(), nargs, nresults, msgh) } {
+            ffi::LUA_OK => Ok(()),
+            code => Err(code),
+        }
+    }
+
+    /// Calls a function in protected mode, allowing the called function to yield.
+    ///
+    /// This behaves like [`.pcall()`] when the called function returns, so the caller must then
+    /// run the continuation `k` itself. When the called function yields, the running C function
+    /// is discarded: this method never returns and, once the coroutine is resumed and the call
+    /// completes, Lua calls `k` with the status [`LUA_YIELD`] (or an error status) and `ctx` to
+    /// finish the work of the C function. See [`§4.5`].
+    ///
+    /// Discarding the C function unwinds its frame without running destructors, so it must not
+    /// hold values needing to be dropped across this call. Prefer
+    /// [`.pcall_with_continuation()`] which handles the context for a Rust closure.
+    ///
+    /// [`.pcall()`]: State::pcall
+    /// [`.pcall_with_continuation()`]: State::pcall_with_continuation
+    /// [`LUA_YIELD`]: status::LUA_YIELD
+    /// [`§4.5`]: https://www.lua.org/manual/5.4/manual.html#4.5
+    // Lua never dereferences the context, it only hands it to the continuation
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn pcallk(
+        &mut self,
+        nargs: i32,
+        nresults: i32,
+        msgh: i32,
+        ctx: ffi::lua_KContext,
+        k: KFunction,
+    ) -> Result<()> {
+        let code = unsafe { ffi::lua_pcallk(self.as_ptr(), nargs, nresults, msgh, ctx, Some(k)) };
+        self.handle_result(code, ())
+    }
+
+    /// Calls a function in protected mode, allowing the called function to yield, and finishes
+    /// with the continuation `k`; returns the number of results of the continuation, to be
+    /// returned by the running C function.
+    ///
+    /// The continuation is called with the outcome of the call, either directly when the called
+    /// function returns, or by Lua when the coroutine is resumed after a yield and the call
+    /// completes (see [`.pcallk()`]). On error, the error message is left on the top of the
+    /// stack. The continuation is boxed until it runs, and leaked if the coroutine is never
+    /// resumed.
+    ///
+    /// As for [`.pcallk()`], the frame of the running C function is discarded when the called
+    /// function yields, so all the state needed afterwards must be moved into `k`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// // calls its argument in protected mode and doubles its result
+    /// unsafe extern "C" fn double(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     state.pcall_with_continuation(0, 1, 0, |state, result| match result {
+    ///         Ok(()) => {
+    ///             let n = state.to_integer::<i64>(-1).unwrap();
+    ///             state.push_integer(n * 2);
+    ///             1
+    ///         }
+    ///         Err(_) => 0,
+    ///     })
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.push_cfunction(double);
+    /// state.set_global("double").unwrap();
+    ///
+    /// state.load_string(r#"
+    ///     local co = coroutine.wrap(function()
+    ///         return double(function() return coroutine.yield(1) + 1 end)
+    ///     end)
+    ///     return co(), co(20)
+    /// "#).unwrap();
+    /// state.pcall(0, 2, 0).unwrap();
+    ///
+    /// assert_eq!(state.to_integer::<i64>(-2), Some(1));
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(42));
+    /// ```
+    ///
+    /// [`.pcallk()`]: State::pcallk
+    pub fn pcall_with_continuation<K>(&mut self, nargs: i32, nresults: i32, msgh: i32, k: K) -> i32
+    where
+        K: FnOnce(&mut State, Result<()>) -> i32 + 'static,
+    {
+        let ctx = Box::into_raw(Box::new(k)) as ffi::lua_KContext;
+        unsafe {
+            let k = Some(continuation::<K> as KFunction);
+            let code = ffi::lua_pcallk(self.as_ptr(), nargs, nresults, msgh, ctx, k);
+            continuation::<K>(self.as_ptr(), code, ctx)
+        }
+    }
+
+    /// Returns a [`Result<T>`](crate::error::Result) based on provided result `code`.
+    ///
+    /// When `code` is not `LUA_OK` or `LUA_YIELD`, it will read the error code from the top of the
+    /// stack and return an [`Err`], otherwise [`Ok`] is returned with the provided `value`.
+    fn handle_result<T>(&self, code: i32, value: T) -> Result<T> {
+        match code {
             ffi::LUA_OK | ffi::LUA_YIELD => Ok(value),
             errcode => {
                 let errmsg = self.as_c_str(-1);
                 let error = format!("{} (code = {})", errmsg.to_string_lossy(), errcode);
-                Err(Error::new(ErrorKind::InvalidData, error))
+                Err(Error::with_code(ErrorKind::InvalidData, errcode, error))
             }
         }
     }
 
+    /// Runs `f` in protected mode, so that an error raised by Lua while running it, typically a
+    /// memory error, is returned as an [`Err`] rather than unwinding through Rust frames.
+    ///
+    /// The values pushed by `f` are left on the top of the stack; on error the stack is restored.
+    /// Fails without running `f` when the stack cannot grow to hold the protected call.
+    fn protect<F: FnOnce(&mut State)>(&mut self, f: F) -> Result<()> {
+        if !self.check_stack(2) {
+            return Err(Error::new(ErrorKind::InvalidInput, "stack overflow"));
+        }
+        let top = self.top();
+        let mut f = Some(f);
+        self.push_cfunction(protected_trampoline::<F>);
+        self.push_light_userdata(&mut f as *mut Option<F> as *mut c_void);
+        let code = unsafe { ffi::lua_pcall(self.as_ptr(), 1, ffi::LUA_MULTRET, 0) };
+        let result = self.handle_result(code, ());
+        if result.is_err() {
+            self.set_top(top);
+        }
+        result
+    }
+
     /// Pushes the C function on the call and call it in protected mode.
     pub fn call_secure(
         &mut self,
@@ -340,436 +1738,2949 @@ impl State {
         self.pcall(nargs, nresults, msgh)
     }
 
-    /// Raises a Lua error, using the value on the top of the stack as the error object.
+    /// Pushes the C function on the call and call it in protected mode, then pops its results as
+    /// an `Output`.
     ///
-    /// This underlying C function does a long jump, and therefore never returns
-    pub fn raise_error<E>(&mut self, error: E) -> !
+    /// The function is called with as many results as `Output` uses slots (see [`Pull::size`]).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn answer(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     state.push_integer(42);
+    ///     state.push_string("forty-two").unwrap();
+    ///     2
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// let (n, s): (i64, String) = state.call_secure_r(0, 0, answer).unwrap();
+    /// assert_eq!(n, 42);
+    /// assert_eq!(s, "forty-two");
+    /// assert_eq!(state.top(), 0);
+    /// ```
+    pub fn call_secure_r<Output: Pull>(
+        &mut self,
+        nargs: i32,
+        msgh: i32,
+        function: CFunction,
+    ) -> Result<Output> {
+        self.call_secure(nargs, Output::size(), msgh, function)?;
+        Output::pop(self)
+    }
+
+    /// Calls the method `method` of the value at the given `index` in protected mode, with the
+    /// value itself as first argument followed by `args`, that is the equivalent of
+    /// `obj:method(args)`, then pops its results as an `Output`.
+    ///
+    /// The method is looked up in protected mode too, so indexing a value which cannot be indexed
+    /// returns an [`Err`]. On error, the stack is restored to its state before the call. Use `()` as
+    /// `Args` to call a method without arguments, or as `Output` to discard its results.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.load_string(r#"
+    ///     local Account = {}
+    ///     Account.__index = Account
+    ///     function Account:deposit(amount) self.balance = self.balance + amount return self.balance end
+    ///     function Account:close() self.balance = 0 end
+    ///     return setmetatable({balance = 100}, Account)
+    /// "#).unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    ///
+    /// let balance: i64 = state.call_method(1, "deposit", 50).unwrap();
+    /// assert_eq!(balance, 150);
+    /// assert!(state.call_method::<_, i64>(1, "withdraw", 50).is_err());
+    /// assert_eq!(state.top(), 1);
+    ///
+    /// state.call_method::<_, ()>(1, "close", ()).unwrap();
+    /// assert_eq!(state.call_method::<_, i64>(1, "deposit", 5).unwrap(), 5);
+    ///
+    /// state.push_nil();
+    /// assert!(state.call_method::<_, i64>(2, "deposit", 1).is_err());
+    /// assert_eq!(state.top(), 2);
+    /// ```
+    pub fn call_method<Args, Output>(
+        &mut self,
+        index: i32,
+        method: &str,
+        args: Args,
+    ) -> Result<Output>
     where
-        E: Into<Box<dyn std::error::Error>>,
+        Args: Push,
+        Output: Pull,
     {
-        let error = error.into().to_string();
-        if let Err(e) = self.push_string(error) {
-            error!("failed to push error string to the stack, {}", e);
+        let index = self.abs_index(index);
+        let mark = self.top();
+        if !self.check_stack(3) {
+            return Err(Error::new(ErrorKind::InvalidInput, "stack overflow"));
         }
-        unsafe { ffi::lua_error(self.as_ptr()) }
+        // the trampoline looks the method up, so an error raised by the lookup is caught
+        self.push_cfunction(method_trampoline);
+        let ret = self.push_string(method).map(|_| ()).and_then(|()| {
+            self.push_value(index);
+            let nargs = args.push(self)?;
+            self.pcall(nargs + 2, Output::size(), 0)?;
+            Output::pop(self)
+        });
+        if ret.is_err() {
+            self.set_top(mark);
+        }
+        ret
+    }
+
+    /// Returns the status of the thread, which is [`LUA_OK`] for a normal thread, an error code if
+    /// the thread finished the execution of a `lua_resume` with an error, or [`LUA_YIELD`] if the
+    /// thread is suspended.
+    ///
+    /// Only functions can be called in threads with status [`LUA_OK`]. A failed [`.pcall()`] does
+    /// not change the status of the thread, it only leaves the error object on the stack.
+    ///
+    /// [`.pcall()`]: State::pcall
+    pub fn status(&self) -> i32 {
+        unsafe { ffi::lua_status(self.as_ptr()) }
+    }
+
+    /// Resets the state so it can be reused, typically after an error: the stack is cleared and,
+    /// when the status of the thread is not [`LUA_OK`], the thread is reset by closing all its
+    /// pending to-be-closed variables, cleaning its call stack and resetting its status.
+    ///
+    /// Returns the error raised while closing a to-be-closed variable, if any; the stack is
+    /// cleared in all cases.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::status::{LUA_ERRRUN, LUA_OK};
+    /// use lua::{ffi, State};
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    ///
+    /// assert!(state.do_string("error('boom')").is_err());
+    /// assert_eq!(state.status(), LUA_OK);
+    /// assert_eq!(state.top(), 1);
+    ///
+    /// state.reset().unwrap();
+    /// assert_eq!(state.top(), 0);
+    /// state.do_string("return 1 + 1").unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(2));
+    ///
+    /// // resets a coroutine which finished with an error
+    /// unsafe extern "C" fn reset(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut co = State::from_ptr(ffi::lua_tothread(ptr, 1), false);
+    ///     assert_eq!(co.status(), LUA_ERRRUN);
+    ///     co.reset().unwrap();
+    ///     assert_eq!(co.status(), LUA_OK);
+    ///     0
+    /// }
+    ///
+    /// state.push_cfunction(reset);
+    /// state.set_global("reset").unwrap();
+    /// state.do_string("
+    ///     local co = coroutine.create(function() error('boom') end)
+    ///     coroutine.resume(co)
+    ///     reset(co)
+    ///     return coroutine.status(co)
+    /// ").unwrap();
+    /// assert_eq!(state.as_bytes(-1), b"dead");
+    /// ```
+    pub fn reset(&mut self) -> Result<()> {
+        let code = if self.status() == ffi::LUA_OK {
+            ffi::LUA_OK
+        } else {
+            unsafe { ffi::lua_resetthread(self.as_ptr()) }
+        };
+        let ret = self.handle_result(code, ());
+        self.set_top(0);
+        ret
+    }
+
+    /// Creates a new thread, a coroutine sharing the global environment of this state, and returns
+    /// it. The stack of this state is left unchanged.
+    ///
+    /// The thread is kept alive in the registry until the returned [`Thread`] is dropped, and it
+    /// cannot outlive this state.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.do_string("x = 21").unwrap();
+    ///
+    /// let mut thread = state.new_thread();
+    /// thread.do_string("return x * 2").unwrap();
+    /// assert_eq!(thread.to_integer::<i64>(-1), Some(42));
+    /// drop(thread);
+    ///
+    /// assert_eq!(state.top(), 0);
+    /// ```
+    pub fn new_thread(&mut self) -> Thread<'_> {
+        let ptr = unsafe { ffi::lua_newthread(self.as_ptr()) };
+        let reference = self.reference();
+        Thread {
+            state: State::from_ptr(ptr, false),
+            reference,
+            _parent: PhantomData,
+        }
+    }
+
+    /// Converts the value at the given `index` to a Lua thread, or returns `None` if the value is
+    /// not a thread.
+    ///
+    /// As for [`.new_thread()`], the returned [`Thread`] keeps a reference to the thread in the
+    /// registry, so the thread stays alive even when the value is removed from the stack. Also
+    /// returns `None` when the stack cannot grow to copy the value into the registry.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::status::{LUA_OK, LUA_YIELD};
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.do_string("
+    ///     return coroutine.create(function(a)
+    ///         local b = coroutine.yield(a * 2)
+    ///         return a + b
+    ///     end)
+    /// ").unwrap();
+    ///
+    /// let mut co = state.to_thread(-1).unwrap();
+    /// co.push_integer(5);
+    /// assert_eq!(co.resume(None, 1).unwrap(), 1);
+    /// assert_eq!(co.status(), LUA_YIELD);
+    /// assert_eq!(co.to_integer::<i64>(-1), Some(10));
+    /// co.pop(1);
+    ///
+    /// co.push_integer(1);
+    /// assert_eq!(co.resume(None, 1).unwrap(), 1);
+    /// assert_eq!(co.status(), LUA_OK);
+    /// assert_eq!(co.to_integer::<i64>(-1), Some(6));
+    /// drop(co);
+    ///
+    /// state.push_integer(1);
+    /// assert!(state.to_thread(-1).is_none());
+    /// ```
+    ///
+    /// [`.new_thread()`]: State::new_thread
+    pub fn to_thread(&mut self, index: i32) -> Option<Thread<'_>> {
+        let ptr = unsafe { ffi::lua_tothread(self.as_ptr(), index) };
+        if ptr.is_null() || !self.check_stack(1) {
+            return None;
+        }
+        self.push_value(index);
+        let reference = self.reference();
+        Some(Thread {
+            state: State::from_ptr(ptr, false),
+            reference,
+            _parent: PhantomData,
+        })
+    }
+
+    /// Starts or resumes this state as a coroutine, and returns the number of values it yielded or
+    /// returned, which are left on the top of its stack.
+    ///
+    /// To start a coroutine, push the main function plus any arguments onto its stack, then call
+    /// `resume` with `nargs` the number of arguments. To resume a suspended coroutine, push the
+    /// values to be passed as results of the yield onto its stack. `from` is the coroutine doing
+    /// this call, if any.
+    ///
+    /// After the call [`.status()`] is [`LUA_YIELD`] if the coroutine yields, or [`LUA_OK`] if it
+    /// finishes its execution. On error, the error object is left on the top of its stack.
+    ///
+    /// [`.status()`]: State::status
+    pub fn resume(&mut self, from: Option<&State>, nargs: i32) -> Result<i32> {
+        let from = from.map_or(ptr::null_mut(), State::as_ptr);
+        let mut nresults = 0;
+        let code = unsafe { ffi::lua_resume(self.as_ptr(), from, nargs, &mut nresults) };
+        self.handle_result(code, nresults)
+    }
+
+    /// Returns `true` if the running coroutine can yield.
+    ///
+    /// A coroutine can yield when it is not the main thread and it is not inside a non-yieldable C
+    /// function.
+    pub fn is_yieldable(&self) -> bool {
+        unsafe { ffi::lua_isyieldable(self.as_ptr()) != 0 }
+    }
+
+    /// Yields the running coroutine, passing the `nresults` values on the top of the stack to the
+    /// caller of `resume`.
+    ///
+    /// This function must only be called from within a C function running on a coroutine. When the
+    /// coroutine is resumed again, it continues by returning to the function that called the C
+    /// function, with the values passed to `resume` as results of the C function.
+    ///
+    /// When the running coroutine cannot yield (see [`.is_yieldable()`]), for instance when called
+    /// off a coroutine, a Lua error is raised instead.
+    ///
+    /// The underlying C function does a long jump, and therefore never returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn pause(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     state.push_integer(42);
+    ///     state.r#yield(1)
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.push_cfunction(pause);
+    /// state.set_global("pause").unwrap();
+    ///
+    /// state.load_string(r#"
+    ///     local co = coroutine.create(function() return pause() + 1 end)
+    ///     local _, a = coroutine.resume(co)
+    ///     local _, b = coroutine.resume(co, 5)
+    ///     return a, b
+    /// "#).unwrap();
+    /// state.pcall(0, 2, 0).unwrap();
+    ///
+    /// assert_eq!(state.to_integer::<i64>(-2), Some(42));
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(6));
+    /// assert!(!state.is_yieldable());
+    /// ```
+    ///
+    /// [`.is_yieldable()`]: State::is_yieldable
+    pub fn r#yield(&mut self, nresults: i32) -> ! {
+        unsafe { ffi::lua_yield(self.as_ptr(), nresults) };
+        unreachable!("yielded from within a hook")
+    }
+
+    /// Pushes `values` and yields them to the caller of `resume`, like [`.yield()`], when the
+    /// running coroutine can yield.
+    ///
+    /// When it cannot yield (see [`.is_yieldable()`]), for instance when called off a coroutine,
+    /// an error is returned rather than raising a Lua error, leaving the stack unchanged.
+    /// Otherwise this method does not return.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::status::LUA_YIELD;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn pair(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     match state.yield_values((1, 2)) {
+    ///         Ok(never) => never,
+    ///         Err(error) => {
+    ///             state.push_string(error.to_string()).unwrap();
+    ///             1
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.push_cfunction(pair);
+    /// state.set_global("pair").unwrap();
+    ///
+    /// state.do_string("return coroutine.create(pair)").unwrap();
+    /// let mut co = state.to_thread(-1).unwrap();
+    /// assert_eq!(co.resume(None, 0).unwrap(), 2);
+    /// assert_eq!(co.status(), LUA_YIELD);
+    /// assert_eq!(co.to_integer::<i64>(-2), Some(1));
+    /// assert_eq!(co.to_integer::<i64>(-1), Some(2));
+    /// drop(co);
+    ///
+    /// state.do_string("return pair()").unwrap();
+    /// assert!(state.as_c_str(-1).to_str().unwrap().contains("outside a coroutine"));
+    /// ```
+    ///
+    /// [`.yield()`]: State::r#yield
+    /// [`.is_yieldable()`]: State::is_yieldable
+    pub fn yield_values<T: Push>(&mut self, values: T) -> Result<!> {
+        if !self.is_yieldable() {
+            let error = "attempt to yield from outside a coroutine";
+            return Err(Error::new(ErrorKind::InvalidInput, error));
+        }
+        let nresults = values.push(self)?;
+        self.r#yield(nresults)
+    }
+
+    /// Returns a traceback of the stack, prefixed by `msg` when provided.
+    ///
+    /// The `level` tells at which level to start the traceback: 0 is the running function, 1 is
+    /// the function that called the running function, etc.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn where_am_i(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     let traceback = state.traceback(Some("here"), 1).unwrap();
+    ///     state.push_string(traceback).unwrap();
+    ///     1
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.push_cfunction(where_am_i);
+    /// state.set_global("where_am_i").unwrap();
+    ///
+    /// state.load_string("local traceback = where_am_i() return traceback").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    ///
+    /// let traceback = state.as_c_str(-1).to_str().unwrap();
+    /// assert!(traceback.starts_with("here\nstack traceback:"));
+    /// assert!(traceback.contains("[string \"local traceback = where_am_i()"));
+    /// assert!(traceback.contains(":1: in main chunk"));
+    /// ```
+    pub fn traceback(&mut self, msg: Option<&str>, level: i32) -> Result<String> {
+        let msg = msg.map(CString::new).transpose()?;
+        let msg = msg.as_ref().map_or(null(), |s| s.as_ptr());
+        unsafe { ffi::luaL_traceback(self.as_ptr(), self.as_ptr(), msg, level) };
+        let traceback = self.as_c_str(-1).to_string_lossy().into_owned();
+        self.pop(1);
+        Ok(traceback)
+    }
+
+    /// Returns a string identifying the current position of the control at the call stack
+    /// `level`, typically in the format `chunkname:currentline: `. Level 0 is the running
+    /// function, level 1 is the function that called the running function, etc.
+    ///
+    /// This is used to prefix error messages, so that they point at the caller like the errors
+    /// raised by Lua.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::Mode;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn fail(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     let location = state.where_str(1);
+    ///     state.raise_error(format!("{}bad thing", location))
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.push_cfunction(fail);
+    /// state.set_global("fail").unwrap();
+    ///
+    /// state.load_bytes(b"local x = 1\nfail()", "=script", Mode::Text).unwrap();
+    /// let e = state.pcall(0, 0, 0).unwrap_err();
+    /// assert!(e.to_string().starts_with("script:2: bad thing"));
+    /// ```
+    pub fn where_str(&mut self, level: i32) -> String {
+        unsafe { ffi::luaL_where(self.as_ptr(), level) };
+        let location = self.as_c_str(-1).to_string_lossy().into_owned();
+        self.pop(1);
+        location
+    }
+
+    /// Raises a Lua error, using the value on the top of the stack as the error object.
+    ///
+    /// This underlying C function does a long jump, and therefore never returns
+    pub fn raise_error<E>(&mut self, error: E) -> !
+    where
+        E: Into<Box<dyn std::error::Error>>,
+    {
+        let error = error.into().to_string();
+        if let Err(e) = self.push_string(error) {
+            error!("failed to push error string to the stack, {}", e);
+        }
+        unsafe { ffi::lua_error(self.as_ptr()) }
+    }
+
+    /// Sets the `handler` receiving the warnings emitted with the Lua function `warn` (see
+    /// [`§4.6`]), replacing any previous handler. The handler lives as long as the Lua state.
+    ///
+    /// The handler is called with each piece of a warning message and a flag telling whether the
+    /// message is to be continued by the next piece. As for the default Lua handler, warnings are
+    /// off until the control message `"@on"` is emitted and are turned off again by `"@off"`;
+    /// control messages are not passed to the handler.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use std::{cell::RefCell, rc::Rc};
+    ///
+    /// use lua::State;
+    ///
+    /// let warnings = Rc::new(RefCell::new(Vec::new()));
+    /// let sink = Rc::clone(&warnings);
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.set_warn_handler(move |msg, _| sink.borrow_mut().push(msg.to_string())).unwrap();
+    ///
+    /// state.load_string("warn('ignored'); warn('@on'); warn('hi')").unwrap();
+    /// state.pcall(0, 0, 0).unwrap();
+    /// assert_eq!(*warnings.borrow(), vec!["hi"]);
+    ///
+    /// // the finalizer of the handler is out of reach of `getmetatable` and safe to call by hand
+    /// state.do_string(r#"
+    ///     local ud = debug.getregistry()["lua.warn.handler"]
+    ///     assert(getmetatable(ud) == false)
+    ///     local gc = debug.getmetatable(ud).__gc
+    ///     gc(io.stdout)
+    ///     gc(ud)
+    ///     gc(ud)
+    /// "#).unwrap();
+    /// assert_eq!(Rc::strong_count(&warnings), 1);
+    ///
+    /// let sink = Rc::clone(&warnings);
+    /// state.set_warn_handler(move |msg, _| sink.borrow_mut().push(msg.to_string())).unwrap();
+    /// state.do_string("warn('@on'); warn('again')").unwrap();
+    /// assert_eq!(*warnings.borrow(), vec!["hi", "again"]);
+    /// ```
+    ///
+    /// [`§4.6`]: https://www.lua.org/manual/5.4/manual.html#4.6
+    pub fn set_warn_handler<F>(&mut self, handler: F) -> Result<()>
+    where
+        F: FnMut(&str, bool) + 'static,
+    {
+        let mut state = StackGuard::new(self);
+        let handler = Box::new(handler);
+
+        // the handler is owned by a userdata kept in the registry, replace the previous handler
+        // in place so the finalizer of the userdata only runs when closing the state
+        unsafe {
+            ffi::lua_getfield(
+                state.as_ptr(),
+                ffi::LUA_REGISTRYINDEX,
+                WARN_HANDLER.as_ptr() as *const i8,
+            );
+            let ud = state.to_userdata(-1) as *mut *mut WarnHandler;
+            let ptr = if ud.is_null() {
+                // the metatable is locked so that `getmetatable` and `setmetatable` cannot reach it
+                state.protect(|state| {
+                    ffi::lua_createtable(state.as_ptr(), 0, 2);
+                    ffi::lua_pushcfunction(state.as_ptr(), drop_warn_handler);
+                    ffi::lua_setfield(state.as_ptr(), -2, b"__gc\0".as_ptr() as *const i8);
+                    ffi::lua_pushboolean(state.as_ptr(), 0);
+                    ffi::lua_setfield(state.as_ptr(), -2, b"__metatable\0".as_ptr() as *const i8);
+                })?;
+                let ud = state.new_userdata(mem::size_of::<*mut WarnHandler>(), 0)?;
+                let ptr = Box::into_raw(Box::new(WarnHandler::new(handler)));
+                *(ud as *mut *mut WarnHandler) = ptr;
+                state.insert(-2);
+                state.set_metatable(-2);
+                ffi::lua_setfield(
+                    state.as_ptr(),
+                    ffi::LUA_REGISTRYINDEX,
+                    WARN_HANDLER.as_ptr() as *const i8,
+                );
+                ptr
+            } else if (*ud).is_null() {
+                // the finalizer was called by hand, the userdata is left without a handler
+                *ud = Box::into_raw(Box::new(WarnHandler::new(handler)));
+                *ud
+            } else {
+                (**ud).handler = handler;
+                *ud
+            };
+            ffi::lua_setwarnf(state.as_ptr(), Some(warn_handler), ptr as *mut c_void);
+        }
+        Ok(())
+    }
+
+    /// Replaces the global `print` function with a function forwarding its output to `sink`,
+    /// e.g. to capture the output of a script or route it to a logger.
+    ///
+    /// As the standard `print`, the function converts its arguments to strings as the Lua
+    /// `tostring` function does and separates them with tabs; `sink` is called once per call of
+    /// `print` with the whole line, without the trailing newline. The sink lives as long as the
+    /// function, that is until it is collected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use std::{cell::RefCell, rc::Rc};
+    ///
+    /// use lua::State;
+    ///
+    /// let output = Rc::new(RefCell::new(String::new()));
+    /// let sink = Rc::clone(&output);
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.set_print(move |line| {
+    ///     let mut output = sink.borrow_mut();
+    ///     output.push_str(line);
+    ///     output.push('\n');
+    /// }).unwrap();
+    ///
+    /// state.do_string("print('a', 1, true) print(nil, 2.5)").unwrap();
+    /// assert_eq!(*output.borrow(), "a\t1\ttrue\nnil\t2.5\n");
+    ///
+    /// output.borrow_mut().clear();
+    /// state.do_string("local t = {} for i = 1, 20 do t[i] = i end print(table.unpack(t))").unwrap();
+    /// let expected: Vec<String> = (1..=20).map(|i| i.to_string()).collect();
+    /// assert_eq!(*output.borrow(), expected.join("\t") + "\n");
+    /// ```
+    pub fn set_print<F>(&mut self, sink: F) -> Result<()>
+    where
+        F: FnMut(&str) + 'static,
+    {
+        let mut state = StackGuard::new(self);
+        let sink: Box<PrintFn> = Box::new(sink);
+        state.push_userdata(sink)?;
+        state.push_cclosure(print, 1);
+        state.set_global("print")
+    }
+
+    /// Pushes a **nil** value onto the stack.
+    pub fn push_nil(&mut self) {
+        unsafe { ffi::lua_pushnil(self.as_ptr()) }
+    }
+
+    /// Pushes a boolean value with value `t` onto the stack.
+    pub fn push_boolean<T: Into<bool>>(&mut self, t: T) {
+        let b = if t.into() { 1 } else { 0 };
+        unsafe { ffi::lua_pushboolean(self.as_ptr(), b) }
+    }
+
+    /// Pushes a new C closure onto the stack. This function receives a pointer to a C function and
+    /// pushes onto the stack a Lua value of type function that, when called, invokes the corresponding
+    /// C function. The parameter n tells how many upvalues this function will have (see [`§4.2`]).
+    ///
+    /// Any function to be callable by Lua must follow the correct protocol to receive its parameters
+    /// and return its results (see [`CFunction`]).
+    ///
+    /// When a C function is created, it is possible to associate some values with it, the so called
+    /// upvalues; these upvalues are then accessible to the function whenever it is called. This
+    /// association is called a C closure (see [`§4.2`]). To create a C closure, first the initial
+    /// values for its upvalues must be pushed onto the stack. (When there are multiple upvalues,
+    /// the first value is pushed first.) Then lua_pushcclosure is called to create and push the C
+    /// function onto the stack, with the argument n telling how many values will be associated with
+    /// the function. lua_pushcclosure also pops these values from the stack.
+    ///
+    /// The maximum value for `n` is 255.
+    ///
+    /// When `n` is zero, this function creates a light C function, which is just a pointer to the C
+    /// function. In that case, it never raises a memory error.
+    ///
+    /// [`§4.2`]: https://www.lua.org/manual/5.4/manual.html#4.2
+    pub fn push_cclosure(&mut self, function: CFunction, n: i32) {
+        unsafe { ffi::lua_pushcclosure(self.as_ptr(), function, n) }
+    }
+
+    /// Pushes a C function onto the stack.
+    pub fn push_cfunction(&mut self, function: CFunction) {
+        unsafe { ffi::lua_pushcfunction(self.as_ptr(), function) }
+    }
+
+    /// Pushes a float with value `t` onto the stack.
+    pub fn push_number<T: Into<f64>>(&mut self, t: T) {
+        let n = t.into();
+        unsafe { ffi::lua_pushnumber(self.as_ptr(), n) }
+    }
+
+    /// Pushes an integer with value `t` onto the stack.
+    pub fn push_integer<T: Into<i64>>(&mut self, t: T) {
+        unsafe { ffi::lua_pushinteger(self.as_ptr(), t.into()) }
+    }
+
+    /// Pushes a light userdata onto the stack.
+    ///
+    /// A light userdata represents a bare pointer, it is a value (like a number): you do not
+    /// create it, it has no individual metatable, and it is not collected (as it was never
+    /// created). Lua does not own the memory at `ptr`, so the caller must keep it valid for as
+    /// long as Lua code may use the value, and free it afterwards.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut handle = 42;
+    /// let ptr = &mut handle as *mut i32 as *mut _;
+    ///
+    /// let mut state = State::new();
+    /// state.push_light_userdata(ptr);
+    /// assert!(state.is_light_userdata(-1));
+    /// assert!(state.is_userdata(-1));
+    /// assert_eq!(state.to_userdata(-1), ptr);
+    /// ```
+    // Lua never dereferences the pointer of a light userdata
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn push_light_userdata(&mut self, ptr: *mut c_void) {
+        unsafe { ffi::lua_pushlightuserdata(self.as_ptr(), ptr) }
+    }
+
+    /// Pushes the string `s` onto the stack.
+    ///
+    /// Lua will make or reuse an internal copy of the given string, so the memory at `s` can be
+    /// freed or reused immediately after the function returns. The string can contain any binary
+    /// data, including embedded zeros.
+    ///
+    /// Returns a reference to the internal copy of the string, or an error when Lua fails to
+    /// allocate the string.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::with_limit(64 * 1_024);
+    /// assert_eq!(state.push_string("hello").unwrap(), b"hello");
+    ///
+    /// let top = state.top();
+    /// let e = state.push_string(vec![b'x'; 1_024 * 1_024]).unwrap_err();
+    /// assert_eq!(e.lua_code(), Some(lua::status::LUA_ERRMEM));
+    /// assert_eq!(state.top(), top);
+    /// ```
+    pub fn push_string<'a, S: AsRef<[u8]>>(&'a mut self, s: S) -> Result<&'a [u8]> {
+        let s = s.as_ref();
+        self.protect(|state| unsafe {
+            ffi::lua_pushlstring(state.as_ptr(), s.as_ptr() as *const i8, s.len());
+        })?;
+        Ok(self.as_bytes(-1))
+    }
+
+    /// Pushes the value `p` onto the stack and returns the number of slots used.
+    pub fn push<T: Push>(&mut self, t: T) -> Result<i32> {
+        t.push(self)
+    }
+
+    /// Pulls the `n` values on the top of the stack, in stack order, that is the value at `-n`
+    /// comes first. The values are left on the stack.
+    ///
+    /// Fails when the stack holds less than `n` values or when any value cannot be pulled as `T`.
+    /// To pull values of different types, pull a tuple instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return 1, 2, 3").unwrap();
+    /// state.pcall(0, 3, 0).unwrap();
+    ///
+    /// assert_eq!(state.pull_multi::<i64>(3).unwrap(), vec![1, 2, 3]);
+    /// assert!(state.pull_multi::<i64>(4).is_err());
+    /// assert!(state.pull_multi::<(i64, i64)>(i32::MAX).is_err());
+    /// assert_eq!(state.top(), 3);
+    /// ```
+    pub fn pull_multi<T: Pull>(&self, n: i32) -> Result<Vec<T>> {
+        let size = T::size();
+        let first = self.top() - self.multi_slots::<T>(n)?;
+        (1..=n).map(|i| T::pull(self, first + i * size)).collect()
+    }
+
+    /// Returns the number of slots used by `n` values of type `T`, failing when the stack does not
+    /// hold that many slots.
+    fn multi_slots<T: Pull>(&self, n: i32) -> Result<i32> {
+        let top = self.top();
+        match n.checked_mul(T::size()) {
+            Some(slots) if n >= 0 && slots <= top => Ok(slots),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot pull {} values from a stack of {}", n, top),
+            )),
+        }
+    }
+
+    /// Pops the `n` values on the top of the stack, in stack order, like [`.pull_multi()`] but
+    /// removing the values from the stack.
+    ///
+    /// Fails, leaving the stack unchanged, when the stack holds less than `n` values or when any
+    /// value cannot be pulled as `T`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push_integer(1);
+    /// state.push_integer(2);
+    /// state.push_integer(3);
+    ///
+    /// assert!(state.drain::<i64>(4).is_err());
+    /// assert!(state.drain::<(i64, i64)>(i32::MAX).is_err());
+    /// assert_eq!(state.drain::<i64>(3).unwrap(), vec![1, 2, 3]);
+    /// assert_eq!(state.top(), 0);
+    /// ```
+    ///
+    /// [`.pull_multi()`]: State::pull_multi
+    pub fn drain<T: Pull>(&mut self, n: i32) -> Result<Vec<T>> {
+        let slots = self.multi_slots::<T>(n)?;
+        let values = self.pull_multi(n)?;
+        self.set_top(self.top() - slots);
+        Ok(values)
+    }
+
+    /// Ensures that the stack has space for at least `n` extra elements, that is, that you can
+    /// safely push up to `n` values into it. It returns `false` if it cannot fulfill the request,
+    /// either because it would cause the stack to be greater than a fixed maximum size (typically
+    /// at least several thousand elements) or because it cannot allocate memory for the extra space.
+    /// This function never shrinks the stack; if the stack already has space for the extra elements,
+    /// it is left unchanged.
+    pub fn check_stack(&mut self, n: i32) -> bool {
+        unsafe { ffi::lua_checkstack(self.as_ptr(), n) != 0 }
+    }
+
+    /// Grows the stack size to `top + sz` elements, raising an error if the stack cannot grow to
+    /// that size. `msg` is an additional text to go into the error message (or `None` for no
+    /// additional text).
+    pub fn try_check_stack(&mut self, sz: i32, msg: &str) {
+        let msg = if !msg.is_empty() {
+            let cs = CString::new(msg).unwrap();
+            cs.as_ptr()
+        } else {
+            null()
+        };
+        unsafe { ffi::luaL_checkstack(self.as_ptr(), sz, msg) }
+    }
+
+    /// Returns `true` if the value at the given `index` is a boolean.
+    pub fn is_boolean(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isboolean(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is a C function.
+    pub fn is_c_function(&self, index: i32) -> bool {
+        unsafe { ffi::lua_iscfunction(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is a function (either C or Lua).
+    pub fn is_function(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isfunction(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is an integer (that is, the value is a
+    /// number and is represented as an integer).
+    pub fn is_integer(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isinteger(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is a light userdata.
+    pub fn is_light_userdata(&self, index: i32) -> bool {
+        unsafe { ffi::lua_islightuserdata(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is **nil**.
+    pub fn is_nil(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isnil(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the given `index` is not valid.
+    pub fn is_none(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isnone(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the given `index` is not valid or if the value at this index is **nil**.
+    pub fn is_none_or_nil(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isnoneornil(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is number or a string convertible to a
+    /// number.
+    pub fn is_number(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isnumber(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is a string or a number (which is always
+    /// convertible to a string).
+    pub fn is_string(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isstring(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is a table.
+    pub fn is_table(&self, index: i32) -> bool {
+        unsafe { ffi::lua_istable(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is a thread.
+    pub fn is_thread(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isthread(self.as_ptr(), index) != 0 }
+    }
+
+    /// Returns `true` if the value at the given `index` is a userdata (either full or light).
+    pub fn is_userdata(&self, index: i32) -> bool {
+        unsafe { ffi::lua_isuserdata(self.as_ptr(), index) != 0 }
+    }
+
+    /// Converts the Lua value at the given `index` to a boolean.
+    ///
+    /// Like all tests in Lua, this returns `true` for any Lua value different from `false` and
+    /// **nil**; otherwise it returns `false`.
+    pub fn to_boolean(&self, index: i32) -> bool {
+        unsafe { ffi::lua_toboolean(self.as_ptr(), index) != 0 }
+    }
+
+    /// Converts the Lua value at the given `index` to a byte slice.
+    ///
+    /// Returns a reference to the string inside the Lua state.
+    pub fn as_bytes<'a>(&'a self, index: i32) -> &'a [u8] {
+        unsafe {
+            let mut len = 0;
+            let ptr = ffi::lua_tolstring(self.as_ptr(), index, &mut len);
+            let data = ptr as *const u8;
+            std::slice::from_raw_parts(data, len)
+        }
+    }
+
+    /// Converts the Lua value at the given `index` to a signed integer.
+    pub fn to_integer<T: num_traits::NumCast>(&self, index: i32) -> Option<T> {
+        let mut isnum = 0;
+        let n = unsafe { ffi::lua_tointegerx(self.as_ptr(), index, &mut isnum) };
+        if isnum == 0 {
+            None
+        } else {
+            num_traits::cast(n)
+        }
+    }
+
+    /// Converts the Lua value at the given `index` to an integer of type `T`, like
+    /// [`.to_integer()`] but failing with a message naming the offending value when the value is not
+    /// a number, is a number with a fractional part, or does not fit in `T`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push_integer(200);
+    /// state.push_integer(300);
+    /// state.push_number(3.7);
+    ///
+    /// assert_eq!(state.to_integer_strict::<u8>(1).unwrap(), 200);
+    ///
+    /// let err = state.to_integer_strict::<u8>(2).unwrap_err();
+    /// assert!(err.to_string().contains("300"));
+    ///
+    /// let err = state.to_integer_strict::<i64>(3).unwrap_err();
+    /// assert!(err.to_string().contains("3.7"));
+    /// ```
+    ///
+    /// [`.to_integer()`]: #method.to_integer
+    pub fn to_integer_strict<T: num_traits::NumCast>(&self, index: i32) -> Result<T> {
+        let mut isnum = 0;
+        let n = unsafe { ffi::lua_tointegerx(self.as_ptr(), index, &mut isnum) };
+        if isnum != 0 {
+            return num_traits::cast(n).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "value {} is out of range for {}",
+                        n,
+                        std::any::type_name::<T>()
+                    ),
+                )
+            });
+        }
+        let msg = match self.to_number::<f64>(index) {
+            Some(n) => format!("value {} has no integer representation", n),
+            None => format!(
+                "value of type {} is not a number",
+                self.info(index).type_name().to_string_lossy()
+            ),
+        };
+        Err(Error::new(ErrorKind::InvalidData, msg))
+    }
+
+    /// Converts the Lua value at the given `index` to a float.
+    pub fn to_number<T: num_traits::NumCast>(&self, index: i32) -> Option<T> {
+        let mut isnum = 0;
+        let n = unsafe { ffi::lua_tonumberx(self.as_ptr(), index, &mut isnum) };
+        if isnum == 0 {
+            None
+        } else {
+            num_traits::cast(n)
+        }
+    }
+
+    /// Converts the Lua value at the given `index` to an integer, like [`.to_integer()`], returning
+    /// `default` when the conversion fails.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push_nil();
+    /// state.push_integer(42);
+    ///
+    /// assert_eq!(state.to_integer_or(-2, 80u16), 80);
+    /// assert_eq!(state.to_integer_or(-1, 80u16), 42);
+    /// ```
+    ///
+    /// [`.to_integer()`]: #method.to_integer
+    pub fn to_integer_or<T: num_traits::NumCast>(&self, index: i32, default: T) -> T {
+        self.to_integer(index).unwrap_or(default)
+    }
+
+    /// Converts the Lua value at the given `index` to a float, like [`.to_number()`], returning
+    /// `default` when the conversion fails.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push_nil();
+    /// state.push_number(0.5);
+    ///
+    /// assert_eq!(state.to_number_or(-2, 1.0), 1.0);
+    /// assert_eq!(state.to_number_or(-1, 1.0), 0.5);
+    /// ```
+    ///
+    /// [`.to_number()`]: #method.to_number
+    pub fn to_number_or<T: num_traits::NumCast>(&self, index: i32, default: T) -> T {
+        self.to_number(index).unwrap_or(default)
+    }
+
+    /// Converts the value at the given `index` to a C function, returning `None` when the value is
+    /// not a C function.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn answer(ptr: *mut ffi::lua_State) -> i32 {
+    ///     ffi::lua_pushinteger(ptr, 42);
+    ///     1
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.push_cfunction(answer);
+    /// state.push_integer(42);
+    ///
+    /// let f = state.to_cfunction(1).unwrap();
+    /// assert_eq!(f as usize, answer as usize);
+    /// assert!(state.to_cfunction(2).is_none());
+    /// ```
+    pub fn to_cfunction(&self, index: i32) -> Option<CFunction> {
+        unsafe { ffi::lua_tocfunction(self.as_ptr(), index) }
+    }
+
+    /// If the value at the given `index` is a full userdata, returns its memory-block address. If
+    /// the value is a light userdata, returns its value (a pointer). Otherwise, returns NULL.
+    pub fn to_userdata(&self, index: i32) -> *mut c_void {
+        unsafe { ffi::lua_touserdata(self.as_ptr(), index) }
+    }
+
+    /// Converts the Lua value at the given `index` to a C string.
+    pub fn as_c_str<'a>(&'a self, index: i32) -> &'a CStr {
+        unsafe { CStr::from_ptr(ffi::lua_tostring(self.as_ptr(), index)) }
+    }
+
+    /// Converts the Lua value at the given `index` to a string, honoring its `__tostring`
+    /// metamethod, as the Lua `tostring` function does.
+    ///
+    /// Unlike [`.as_c_str()`], numbers are not converted in place and any value can be converted.
+    /// The conversion runs in protected mode, so an error raised by the metamethod is returned as
+    /// an [`Err`]. The stack is left unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.load_string(r#"
+    ///     local point = setmetatable({x = 1, y = 2}, {
+    ///         __tostring = function(p) return "(" .. p.x .. ", " .. p.y .. ")" end,
+    ///     })
+    ///     local broken = setmetatable({}, {__tostring = function() error("boom") end})
+    ///     return point, broken
+    /// "#).unwrap();
+    /// state.pcall(0, 2, 0).unwrap();
+    ///
+    /// assert_eq!(state.to_string_meta(1).unwrap(), "(1, 2)");
+    /// assert!(state.to_string_meta(2).unwrap_err().to_string().contains("boom"));
+    /// assert_eq!(state.top(), 2);
+    /// ```
+    ///
+    /// [`.as_c_str()`]: State::as_c_str
+    pub fn to_string_meta(&mut self, index: i32) -> Result<String> {
+        let index = self.abs_index(index);
+        let mut state = StackGuard::new(self);
+        state.push_cfunction(to_string_meta);
+        state.push_value(index);
+        state.pcall(1, 1, 0)?;
+        String::pull(&state, -1)
+    }
+
+    /// Returns the [`Info`] of the stack element at the given `index`.
+    pub fn info(&self, idx: i32) -> Info<'_> {
+        unsafe {
+            let state = self.as_ptr();
+
+            let tp = ffi::lua_type(state, idx);
+
+            let name = CStr::from_ptr(ffi::lua_typename(state, tp));
+
+            Info {
+                idx,
+                tp,
+                name,
+                state: self.ptr,
+            }
+        }
+    }
+
+    /// Returns the index of the top element in the stack.
+    ///
+    /// Because indices start at 1, this result is equal to the number of elements in the stack; in
+    /// particular, 0 means an empty stack.
+    pub fn top(&self) -> i32 {
+        unsafe { ffi::lua_gettop(self.as_ptr()) }
+    }
+
+    /// Converts the acceptable `index` into an equivalent absolute index (that is, one that does not
+    /// depend on the stack size).
+    pub fn abs_index(&self, index: i32) -> i32 {
+        unsafe { ffi::lua_absindex(self.as_ptr(), index) }
+    }
+
+    /// Accepts any `index`, or 0, and sets the stack top to this `index`. If the new top is greater
+    /// than the old one, then the new elements are filled with **nil**. If `index` is 0, then all
+    /// stack elements are removed.
+    ///
+    /// This function can run arbitrary code when removing an index marked as to-be-closed from the
+    /// stack.
+    pub fn set_top(&mut self, index: i32) {
+        unsafe { ffi::lua_settop(self.as_ptr(), index) }
+    }
+
+    /// Pops n elements from the stack.
+    ///
+    /// This can run arbitrary code when removing an index marked as to-be-closed from the stack.
+    pub fn pop(&mut self, n: i32) {
+        unsafe { ffi::lua_pop(self.as_ptr(), n) }
+    }
+
+    /// Pulls the value on the top of the stack as `T` using its [`Pull`] implementation, then pops
+    /// it, even when it cannot be pulled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push_string("hello").unwrap();
+    /// let s: String = state.pop_value().unwrap();
+    /// assert_eq!(s, "hello");
+    /// assert_eq!(state.top(), 0);
+    /// ```
+    pub fn pop_value<T: Pull>(&mut self) -> Result<T> {
+        T::pop(self)
+    }
+
+    /// Pushes a copy of the element at the given `index` onto the stack.
+    pub fn push_value(&mut self, index: i32) {
+        unsafe { ffi::lua_pushvalue(self.as_ptr(), index) }
+    }
+
+    /// Rotates the stack elements between the valid index idx and the top of the stack.
+    ///
+    /// The elements are rotated `n` positions in the direction of the top, for a positive `n`, or
+    /// `-n` positions in the direction of the bottom, for a negative `n`. The absolute value of `n`
+    /// must not be greater than the size of the slice being rotated.
+    ///
+    /// ## Pseudo-index support
+    ///
+    /// This function cannot be called with a pseudo-index, because a pseudo-index is not an actual
+    /// stack position.
+    pub fn rotate(&mut self, index: i32, n: i32) {
+        unsafe { ffi::lua_rotate(self.as_ptr(), index, n) }
+    }
+
+    /// Removes the element at the given valid `index`, shifting down the elements above this `index`
+    /// to fill the gap.
+    ///
+    /// ## Pseudo-index support
+    ///
+    /// This function cannot be called with a pseudo-index, because a pseudo-index is not an actual
+    /// stack position.
+    pub fn remove(&mut self, index: i32) {
+        unsafe { ffi::lua_remove(self.as_ptr(), index) }
+    }
+
+    /// Moves the top element into the given valid `index`, shifting up the elements above this
+    /// `index` to open space.
+    ///
+    /// ## Pseudo-index support
+    ///
+    /// This function cannot be called with a pseudo-index, because a pseudo-index is not an actual
+    /// stack position.
+    pub fn insert(&mut self, index: i32) {
+        unsafe { ffi::lua_insert(self.as_ptr(), index) }
+    }
+
+    /// Reverses the order of the `n` elements on the top of the stack, or of the whole stack when
+    /// it holds fewer than `n` elements. Does nothing when `n` is 1 or less.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push_integer(1);
+    /// state.push_integer(2);
+    /// state.push_integer(3);
+    ///
+    /// state.reverse(3);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(1));
+    /// assert_eq!(state.to_integer::<i64>(-2), Some(2));
+    /// assert_eq!(state.to_integer::<i64>(-3), Some(3));
+    ///
+    /// state.reverse(1);
+    /// state.reverse(0);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(1));
+    ///
+    /// state.reverse(10);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(3));
+    /// assert_eq!(state.to_integer::<i64>(-3), Some(1));
+    /// assert_eq!(state.top(), 3);
+    /// ```
+    pub fn reverse(&mut self, n: i32) {
+        let n = n.min(self.top());
+        if n <= 1 {
+            return;
+        }
+        // move the top element down into each position of the window, from its bottom up
+        let bottom = self.top() - n + 1;
+        for index in bottom..self.top() {
+            self.rotate(index, 1);
+        }
+    }
+
+    /// Pushes the value `t` and moves it into the given valid `index`, shifting up the elements
+    /// above this `index` to open space. A value using several slots keeps its slots in order.
+    ///
+    /// ## Pseudo-index support
+    ///
+    /// This function cannot be called with a pseudo-index, because a pseudo-index is not an actual
+    /// stack position.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    ///
+    /// // the equivalent of `("%d-%d"):format(4, 2)`, the receiver is inserted below the arguments
+    /// state.get_global("string").unwrap();
+    /// state.get_field(-1, "format").unwrap();
+    /// state.push_integer(4);
+    /// state.push_integer(2);
+    /// state.push_at(-2, &b"%d-%d"[..]).unwrap();
+    ///
+    /// state.pcall(3, 1, 0).unwrap();
+    /// assert_eq!(state.as_bytes(-1), b"4-2");
+    /// ```
+    pub fn push_at<T: Push>(&mut self, index: i32, t: T) -> Result<()> {
+        let index = self.abs_index(index);
+        let n = t.push(self)?;
+        self.rotate(index, n);
+        Ok(())
+    }
+
+    /// Moves the top element into the given valid `index` without shifting any element (therefore
+    /// replacing the value at that given `index`), and then pops the top element.
+    pub fn replace(&mut self, index: i32) {
+        unsafe { ffi::lua_replace(self.as_ptr(), index) }
+    }
+
+    /// Copies the element at index `fromidx` into the valid index `toidx`, replacing the value at
+    /// that position. Values at other positions are not affected.
+    pub fn copy(&mut self, fromidx: i32, toidx: i32) {
+        unsafe { ffi::lua_copy(self.as_ptr(), fromidx, toidx) }
+    }
+
+    /// Swaps the values at the valid indices `a` and `b`. Values at other positions are not
+    /// affected, and nothing happens when both indices refer to the same position.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push_integer(1);
+    /// state.push_integer(2);
+    /// state.swap(-2, -1);
+    ///
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(1));
+    /// assert_eq!(state.to_integer::<i64>(-2), Some(2));
+    /// assert_eq!(state.top(), 2);
+    /// ```
+    pub fn swap(&mut self, a: i32, b: i32) {
+        let a = self.abs_index(a);
+        let b = self.abs_index(b);
+        if a != b {
+            self.push_value(a);
+            self.copy(b, a);
+            self.replace(b);
+        }
+    }
+
+    /// Concatenates the `n` values at the top of the stack, pops them, and leaves the result on the
+    /// top. If `n` is 1, the result is the single value on the stack (that is, the function does
+    /// nothing); if `n` is 0, the result is the empty string. Concatenation is performed following
+    /// the usual semantics of Lua (see [`§3.4.6`]).
+    ///
+    /// [`§3.4.6`]: https://www.lua.org/manual/5.4/manual.html#3.4.6
+    pub fn concat(&mut self, n: i32) {
+        unsafe { ffi::lua_concat(self.as_ptr(), n) }
+    }
+
+    /// Joins the strings `parts` with the separator `sep` using Lua concatenation, leaving the
+    /// stack as it was.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// assert_eq!(state.join(&["a", "b", "c"], "-").unwrap(), "a-b-c");
+    /// assert_eq!(state.join(&[], "-").unwrap(), "");
+    /// assert_eq!(state.top(), 0);
+    /// ```
+    pub fn join(&mut self, parts: &[&str], sep: &str) -> Result<String> {
+        let mut state = StackGuard::new(self);
+        let n = (2 * parts.len()).saturating_sub(1) as i32;
+        if !state.check_stack(n) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "too many parts to join",
+            ));
+        }
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                state.push_string(sep)?;
+            }
+            state.push_string(part)?;
+        }
+        state.concat(n);
+        String::pull(&state, -1)
+    }
+
+    /// Returns an iterator over the stack (from bottom to top).
+    pub fn iter(&self) -> Iter<'_> {
+        let top = self.top();
+        Iter {
+            state: self,
+            n: 1,
+            top,
+        }
+    }
+
+    /// Creates an iterator which dumps the values on a Lua stack from bottom to top.
+    pub fn dump(&self) -> Dump<'_> {
+        Dump { iter: self.iter() }
+    }
+
+    /// Pushes onto the stack the value of the global name. Returns the type of that value.
+    pub fn get_global<T: Into<Vec<u8>>>(&mut self, name: T) -> Result<Type> {
+        let name = CString::new(name)?;
+        Ok(unsafe { ffi::lua_getglobal(self.as_ptr(), name.as_ptr()) }.into())
+    }
+
+    pub fn set_global<T: Into<Vec<u8>>>(&mut self, name: T) -> Result<()> {
+        let name = CString::new(name)?;
+        Ok(unsafe { ffi::lua_setglobal(self.as_ptr(), name.as_ptr()) })
+    }
+
+    /// Sets the table at the given `index` as the new global table, that is the table used by
+    /// [`.get_global()`] and [`.set_global()`] and the `_ENV` of the chunks loaded afterwards.
+    ///
+    /// Functions already loaded retain their `_ENV` upvalue, so they keep on accessing the
+    /// previous global table; only replace the global table before loading untrusted code.
+    ///
+    /// Fails, leaving the global table unchanged, when the value is not a table.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    ///
+    /// state.create_table(0, 1).unwrap();
+    /// state.get_global("print").unwrap();
+    /// state.set_field(-2, "print").unwrap();
+    /// state.set_globals(-1).unwrap();
+    /// state.pop(1);
+    ///
+    /// state.load_string("return os, print").unwrap();
+    /// state.pcall(0, 2, 0).unwrap();
+    /// assert!(state.is_nil(-2));
+    /// assert!(state.is_function(-1));
+    ///
+    /// state.push_integer(1);
+    /// assert!(state.set_globals(-1).is_err());
+    /// ```
+    ///
+    /// [`.get_global()`]: State::get_global
+    /// [`.set_global()`]: State::set_global
+    pub fn set_globals(&mut self, index: i32) -> Result<()> {
+        if !self.is_table(index) {
+            return Err(Error::new(ErrorKind::InvalidInput, "value is not a table"));
+        }
+        self.push_value(index);
+        self.raw_set_index(ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+        Ok(())
+    }
+
+    /// Pushes onto the stack the value of the global name, like [`.get_global()`], but returns a
+    /// [`Popped`] guard that pops the value again when it goes out of scope.
+    ///
+    /// [`.get_global()`]: State::get_global
+    pub fn get_global_guarded<T: Into<Vec<u8>>>(&mut self, name: T) -> Result<Popped<'_, Type>> {
+        let mark = self.top();
+        let tp = self.get_global(name)?;
+        Ok(Popped::new(mark, tp, self))
+    }
+
+    /// Registers the Rust function `f` as the global `name`, wrapping it in a [`RustFunction`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state
+    ///     .register_fn("add", |(a, b): (i64, i64)| Ok(a + b))
+    ///     .unwrap();
+    ///
+    /// state.load_string("return add(1, 2)").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(3));
+    /// ```
+    pub fn register_fn<Args, Output, F>(&mut self, name: &str, f: F) -> Result<()>
+    where
+        F: Fn(Args) -> Result<Output> + 'static,
+        Args: Pull,
+        Output: Push,
+    {
+        self.push(RustFunction::new(f))?;
+        self.set_global(name)
+    }
+
+    /// Returns a [`LibraryBuilder`] registering a table of Rust functions as the global `name`,
+    /// similar to `luaL_newlib`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state
+    ///     .new_library("math2")
+    ///     .function("double", |(n,): (i64,)| Ok(n * 2))
+    ///     .function("square", |(n,): (i64,)| Ok(n * n))
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// state.load_string("return math2.double(3), math2.square(3)").unwrap();
+    /// state.pcall(0, 2, 0).unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-2), Some(6));
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(9));
+    /// ```
+    pub fn new_library(&mut self, name: &str) -> LibraryBuilder<'_> {
+        LibraryBuilder::new(self, name)
+    }
+
+    /// Returns a [`ClosureBuilder`] pushing the C function `function` as a closure, with the
+    /// upvalues added to the builder.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn greet(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     state.push_value(state.upvalue_index(1));
+    ///     state.push_value(state.upvalue_index(2));
+    ///     state.concat(2);
+    ///     1
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.closure(greet).upvalue(&b"hello, "[..]).upvalue(&b"world"[..]).build().unwrap();
+    /// state.set_global("greet").unwrap();
+    ///
+    /// state.load_string("return greet()").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    /// assert_eq!(state.as_bytes(-1), b"hello, world");
+    /// ```
+    pub fn closure(&mut self, function: CFunction) -> ClosureBuilder<'_> {
+        ClosureBuilder::new(self, function)
     }
 
-    /// Pushes a **nil** value onto the stack.
-    pub fn push_nil(&mut self) {
-        unsafe { ffi::lua_pushnil(self.as_ptr()) }
+    /// Pushes onto the stack the value `t[k]`, where `t` is the value at the given index and `k` is
+    /// the value on the top of the stack.
+    ///
+    /// This methods pops the key from the stack, pushing the resulting value in its place. As in
+    /// Lua, this function may trigger a metamethod for the "index" event (see [`§2.4`]).
+    ///
+    /// Returns the type of the pushed value.
+    ///
+    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
+    pub fn get_table(&mut self, index: i32) -> Type {
+        unsafe { ffi::lua_gettable(self.as_ptr(), index) }.into()
     }
 
-    /// Pushes a boolean value with value `t` onto the stack.
-    pub fn push_boolean<T: Into<bool>>(&mut self, t: T) {
-        let b = if t.into() { 1 } else { 0 };
-        unsafe { ffi::lua_pushboolean(self.as_ptr(), b) }
+    /// Pushes onto the stack the value `t[k]`, where `t` is the value at the given `index`. As in
+    /// Lua, this function may trigger a metamethod for the "index" event (see [`§2.4`]).
+    ///
+    /// Returns the type of the pushed value.
+    ///
+    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
+    pub fn get_field<T: Into<Vec<u8>>>(&mut self, index: i32, key: T) -> Result<Type> {
+        let key = CString::new(key)?;
+        Ok(unsafe { ffi::lua_getfield(self.as_ptr(), index, key.as_ptr()) }.into())
     }
 
-    /// Pushes a new C closure onto the stack. This function receives a pointer to a C function and
-    /// pushes onto the stack a Lua value of type function that, when called, invokes the corresponding
-    /// C function. The parameter n tells how many upvalues this function will have (see [`§4.2`]).
+    /// Ensures that the value `t[key]`, where `t` is the value at the given `index`, is a table,
+    /// and pushes that table onto the stack. Returns `true` if it finds a previous table there and
+    /// `false` if it creates a new table.
     ///
-    /// Any function to be callable by Lua must follow the correct protocol to receive its parameters
-    /// and return its results (see [`CFunction`]).
+    /// # Examples
     ///
-    /// When a C function is created, it is possible to associate some values with it, the so called
-    /// upvalues; these upvalues are then accessible to the function whenever it is called. This
-    /// association is called a C closure (see [`§4.2`]). To create a C closure, first the initial
-    /// values for its upvalues must be pushed onto the stack. (When there are multiple upvalues,
-    /// the first value is pushed first.) Then lua_pushcclosure is called to create and push the C
-    /// function onto the stack, with the argument n telling how many values will be associated with
-    /// the function. lua_pushcclosure also pops these values from the stack.
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
     ///
-    /// The maximum value for `n` is 255.
+    /// let mut state = State::new();
+    /// state.new_table().unwrap();
     ///
-    /// When `n` is zero, this function creates a light C function, which is just a pointer to the C
-    /// function. In that case, it never raises a memory error.
+    /// assert!(!state.get_subtable(-1, "config").unwrap());
+    /// state.push_integer(8);
+    /// state.set_field(-2, "width").unwrap();
+    /// state.pop(1);
     ///
-    /// [`§4.2`]: https://www.lua.org/manual/5.4/manual.html#4.2
-    pub fn push_cclosure(&mut self, function: CFunction, n: i32) {
-        unsafe { ffi::lua_pushcclosure(self.as_ptr(), function, n) }
+    /// assert!(state.get_subtable(-1, "config").unwrap());
+    /// state.get_field(-1, "width").unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(8));
+    /// ```
+    pub fn get_subtable<T: Into<Vec<u8>>>(&mut self, index: i32, key: T) -> Result<bool> {
+        let key = CString::new(key)?;
+        Ok(unsafe { ffi::luaL_getsubtable(self.as_ptr(), index, key.as_ptr()) != 0 })
     }
 
-    /// Pushes a C function onto the stack.
-    pub fn push_cfunction(&mut self, function: CFunction) {
-        unsafe { ffi::lua_pushcfunction(self.as_ptr(), function) }
+    /// Pushes onto the stack the value `t[k]`, like [`.get_field()`], but returns a [`Popped`]
+    /// guard that pops the value again when it goes out of scope, even when a later operation
+    /// fails.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{state::Pull, State};
+    ///
+    /// fn read_width(state: &mut State) -> lua::Result<i32> {
+    ///     let width = state.get_field_guarded(-1, "width")?;
+    ///     i32::pull(&width, -1)
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.new_table().unwrap();
+    /// state.push_string("wide").unwrap();
+    /// state.set_field(-2, "width").unwrap();
+    ///
+    /// assert!(read_width(&mut state).is_err());
+    /// assert_eq!(state.top(), 1);
+    /// ```
+    ///
+    /// [`.get_field()`]: State::get_field
+    pub fn get_field_guarded<T: Into<Vec<u8>>>(
+        &mut self,
+        index: i32,
+        key: T,
+    ) -> Result<Popped<'_, Type>> {
+        let mark = self.top();
+        let tp = self.get_field(index, key)?;
+        Ok(Popped::new(mark, tp, self))
     }
 
-    /// Pushes a float with value `t` onto the stack.
-    pub fn push_number<T: Into<f64>>(&mut self, t: T) {
-        let n = t.into();
-        unsafe { ffi::lua_pushnumber(self.as_ptr(), n) }
+    /// Does the equivalent to `t[k] = v`, where `t` is the value at the given index, `v` is the
+    /// value on the top of the stack, and `k` is the value just below the top.
+    ///
+    /// This function pops both the key and the value from the stack. As in Lua, this function may
+    /// trigger a metamethod for the "newindex" event (see [`§2.4`]).
+    ///
+    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
+    pub fn set_table(&mut self, index: i32) {
+        unsafe { ffi::lua_settable(self.as_ptr(), index) }
     }
 
-    /// Pushes an integer with value `t` onto the stack.
-    pub fn push_integer<T: Into<i64>>(&mut self, t: T) {
-        unsafe { ffi::lua_pushinteger(self.as_ptr(), t.into()) }
+    /// Does the equivalent to `t[k] = v`, where `t` is the value at the given `index` and `v` is
+    /// the value on the top of the stack.
+    ///
+    /// This function pops the value from the stack. As in Lua, this function may trigger a
+    /// metamethod for the "newindex" event (see [`§2.4`]).
+    ///
+    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
+    pub fn set_field<T: Into<Vec<u8>>>(&mut self, index: i32, key: T) -> Result<()> {
+        let key = CString::new(key)?;
+        Ok(unsafe { ffi::lua_setfield(self.as_ptr(), index, key.as_ptr()) })
     }
 
-    /// Pushes the string `s` onto the stack.
+    /// Does the equivalent to `t[k] = v`, where `t` is the value at the given `index` and `v` is
+    /// the given `value`, like [`.set_field()`] but pushing the value itself.
     ///
-    /// Lua will make or reuse an internal copy of the given string, so the memory at `s` can be
-    /// freed or reused immediately after the function returns. The string can contain any binary
-    /// data, including embedded zeros.
+    /// The assignment runs in protected mode, so an error raised by a metamethod for the
+    /// "newindex" event is returned as an [`Err`]. Fails as well when the value does not use
+    /// exactly one slot. On error, the stack is left unchanged.
     ///
-    /// Returns a reference to the internal copy of the string.
-    pub fn push_string<'a, S: AsRef<[u8]>>(&'a mut self, s: S) -> Result<&'a [u8]> {
-        let s = s.as_ref();
-        let s = unsafe {
-            let cs = ffi::lua_pushlstring(self.as_ptr(), s.as_ptr() as *const i8, s.len());
-            if cs.is_null() {
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.new_table().unwrap();
+    /// state.set_field_value(-1, "width", 200).unwrap();
+    /// state.set_field_value(-1, "visible", true).unwrap();
+    ///
+    /// assert_eq!(state.get_field_value::<u16, _>(-1, "width").unwrap(), 200);
+    /// assert!(state.get_field_value::<bool, _>(-1, "visible").unwrap());
+    /// assert_eq!(state.get_field_value::<Option<i32>, _>(-1, "height").unwrap(), None);
+    /// assert_eq!(state.top(), 1);
+    ///
+    /// assert!(state.set_field_value(-1, "size", (200, 100)).is_err());
+    /// assert!(state.get_field_value::<(i32, i32), _>(-1, "width").is_err());
+    /// assert_eq!(state.top(), 1);
+    ///
+    /// state.open_libs();
+    /// state.do_string(r#"
+    ///     locked = setmetatable({}, {
+    ///         __index = function() error("no field") end,
+    ///         __newindex = function() error("read-only") end,
+    ///     })
+    /// "#).unwrap();
+    /// state.get_global("locked").unwrap();
+    /// assert!(state.set_field_value(-1, "width", 200).is_err());
+    /// assert!(state.get_field_value::<i32, _>(-1, "width").is_err());
+    /// assert_eq!(state.top(), 2);
+    /// ```
+    ///
+    /// [`.set_field()`]: State::set_field
+    pub fn set_field_value<V, T>(&mut self, index: i32, key: T, value: V) -> Result<()>
+    where
+        V: Push,
+        T: Into<Vec<u8>>,
+    {
+        let index = self.abs_index(index);
+        let key = CString::new(key)?;
+        let mark = self.top();
+        if !self.check_stack(3) {
+            return Err(Error::new(ErrorKind::InvalidInput, "stack overflow"));
+        }
+        self.push_cfunction(set_table_trampoline);
+        self.push_value(index);
+        let ret = self.push_string(key.as_bytes()).map(|_| ()).and_then(|()| {
+            if value.push(self)? != 1 {
                 return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    "unexpected NULL while pushing string",
+                    ErrorKind::InvalidInput,
+                    "value must use a single slot",
                 ));
             }
-            let len = libc::strlen(cs);
-            let data = cs as *const u8;
-            std::slice::from_raw_parts(data, len)
-        };
-        Ok(s)
+            self.pcall(3, 0, 0)
+        });
+        if ret.is_err() {
+            self.set_top(mark);
+        }
+        ret
     }
 
-    /// Pushes the value `p` onto the stack and returns the number of slots used.
-    pub fn push<T: Push>(&mut self, t: T) -> Result<i32> {
-        t.push(self)
+    /// Pulls the value `t[k]` as `V`, where `t` is the value at the given `index`, leaving the
+    /// stack as it was. As [`.get_field()`], this function may trigger a metamethod for the
+    /// "index" event.
+    ///
+    /// The lookup runs in protected mode, so an error raised by such a metamethod is returned as
+    /// an [`Err`]. Fails as well when `V` does not use exactly one slot.
+    ///
+    /// [`.get_field()`]: State::get_field
+    pub fn get_field_value<V, T>(&mut self, index: i32, key: T) -> Result<V>
+    where
+        V: Pull,
+        T: Into<Vec<u8>>,
+    {
+        if V::size() != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "value must use a single slot",
+            ));
+        }
+        let index = self.abs_index(index);
+        let key = CString::new(key)?;
+        let mark = self.top();
+        if !self.check_stack(3) {
+            return Err(Error::new(ErrorKind::InvalidInput, "stack overflow"));
+        }
+        self.push_cfunction(get_table_trampoline);
+        self.push_value(index);
+        let ret = self.push_string(key.as_bytes()).map(|_| ()).and_then(|()| {
+            self.pcall(2, 1, 0)?;
+            V::pop(self)
+        });
+        if ret.is_err() {
+            self.set_top(mark);
+        }
+        ret
     }
 
-    /// Ensures that the stack has space for at least `n` extra elements, that is, that you can
-    /// safely push up to `n` values into it. It returns `false` if it cannot fulfill the request,
-    /// either because it would cause the stack to be greater than a fixed maximum size (typically
-    /// at least several thousand elements) or because it cannot allocate memory for the extra space.
-    /// This function never shrinks the stack; if the stack already has space for the extra elements,
-    /// it is left unchanged.
-    pub fn check_stack(&mut self, n: i32) -> bool {
-        unsafe { ffi::lua_checkstack(self.as_ptr(), n) != 0 }
+    /// Appends the elements of the sequence at the given `index` to `out`, returning the number of
+    /// elements added.
+    ///
+    /// The length of the sequence is its raw length (see [`§3.4.7`]), it is used to reserve
+    /// capacity in `out` before pulling any element. The elements are read without invoking
+    /// metamethods. Fails when the value is not a table, when `T` does not use exactly one slot or
+    /// when an element cannot be pulled as `T`; `out` is then left as it was.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return {1, 2}, {3, 4, 5}").unwrap();
+    /// state.pcall(0, 2, 0).unwrap();
+    ///
+    /// let mut out: Vec<i32> = Vec::new();
+    /// assert_eq!(state.extend_vec(-2, &mut out).unwrap(), 2);
+    /// assert_eq!(state.extend_vec(-1, &mut out).unwrap(), 3);
+    /// assert_eq!(out, vec![1, 2, 3, 4, 5]);
+    ///
+    /// state.push_string("12345").unwrap();
+    /// assert!(state.extend_vec(-1, &mut out).is_err());
+    /// assert_eq!(out.len(), 5);
+    ///
+    /// state.load_string("return {6, 'seven'}").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    /// assert!(state.extend_vec(-1, &mut out).is_err());
+    /// assert_eq!(out.len(), 5);
+    ///
+    /// let mut pairs: Vec<(i64, i64)> = Vec::new();
+    /// assert!(state.extend_vec(-3, &mut pairs).is_err());
+    /// assert_eq!(state.top(), 4);
+    /// ```
+    ///
+    /// [`§3.4.7`]: https://www.lua.org/manual/5.4/manual.html#3.4.7
+    pub fn extend_vec<T: Pull>(&mut self, index: i32, out: &mut Vec<T>) -> Result<usize> {
+        let index = self.abs_index(index);
+        if !self.is_table(index) {
+            return Err(Error::new(ErrorKind::InvalidData, "value is not a table"));
+        }
+        if T::size() != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "elements must use a single slot",
+            ));
+        }
+        let start = out.len();
+        let len = self.raw_len(index);
+        out.reserve(len);
+        for i in 1..=len {
+            self.raw_get_index(index, i as i64);
+            match T::pop(self) {
+                Ok(value) => out.push(value),
+                Err(e) => {
+                    out.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(len)
     }
 
-    /// Grows the stack size to `top + sz` elements, raising an error if the stack cannot grow to
-    /// that size. `msg` is an additional text to go into the error message (or `None` for no
-    /// additional text).
-    pub fn try_check_stack(&mut self, sz: i32, msg: &str) {
-        let msg = if !msg.is_empty() {
-            let cs = CString::new(msg).unwrap();
-            cs.as_ptr()
-        } else {
-            null()
-        };
-        unsafe { ffi::luaL_checkstack(self.as_ptr(), sz, msg) }
+    /// Pulls the elements `t[1], ..., t[#t]` of the table at the given `index` into a new vector,
+    /// stopping at the first **nil** element.
+    ///
+    /// The length of the sequence is its raw length and the elements are read without invoking
+    /// metamethods. Fails when the value is not a table, when `T` does not use exactly one slot
+    /// and, naming the position of the element, when an element cannot be pulled as `T`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return {10, 20, 30}, {1, 'two', 3}").unwrap();
+    /// state.pcall(0, 2, 0).unwrap();
+    ///
+    /// assert_eq!(state.to_vec::<i32>(1).unwrap(), vec![10, 20, 30]);
+    ///
+    /// let error = state.to_vec::<i32>(2).unwrap_err();
+    /// assert!(error.to_string().contains("element 2"));
+    /// assert_eq!(state.top(), 2);
+    ///
+    /// state.push_string("abc").unwrap();
+    /// assert!(state.to_vec::<i32>(3).is_err());
+    ///
+    /// assert!(state.to_vec::<(i32, i32)>(1).is_err());
+    /// assert_eq!(state.top(), 3);
+    /// ```
+    pub fn to_vec<T: Pull>(&mut self, index: i32) -> Result<Vec<T>> {
+        let index = self.abs_index(index);
+        if !self.is_table(index) {
+            return Err(Error::new(ErrorKind::InvalidData, "value is not a table"));
+        }
+        if T::size() != 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "elements must use a single slot",
+            ));
+        }
+        let len = self.raw_len(index);
+        let mut out: Vec<T> = Vec::with_capacity(len);
+        for i in 1..=len {
+            if self.raw_get_index(index, i as i64) == Type::Nil {
+                self.pop(1);
+                break;
+            }
+            let value = T::pop(self)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("element {}: {}", i, e)))?;
+            out.push(value);
+        }
+        Ok(out)
     }
 
-    /// Returns `true` if the value at the given `index` is a boolean.
-    pub fn is_boolean(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isboolean(self.as_ptr(), index) != 0 }
+    /// Pulls all the key-value pairs of the table at the given `index` into a new vector, in
+    /// traversal order, which is unspecified. This is the counterpart of [`.to_vec()`] for
+    /// map-like tables.
+    ///
+    /// Fails, rather than skipping the pair, when a key or a value cannot be pulled as `K` or `V`,
+    /// as when pulling a `Vec<(K, V)>`. The stack is left unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return {a = 1, b = 2}").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    ///
+    /// let mut entries = state.entries::<String, i32>(-1).unwrap();
+    /// entries.sort();
+    /// assert_eq!(entries, vec![("a".to_string(), 1), ("b".to_string(), 2)]);
+    ///
+    /// assert!(state.entries::<i64, i32>(-1).is_err());
+    /// assert_eq!(state.top(), 1);
+    /// ```
+    ///
+    /// [`.to_vec()`]: State::to_vec
+    pub fn entries<K: Pull, V: Pull>(&self, index: i32) -> Result<Vec<(K, V)>> {
+        Vec::<(K, V)>::pull(self, index)
     }
 
-    /// Returns `true` if the value at the given `index` is a C function.
-    pub fn is_c_function(&self, index: i32) -> bool {
-        unsafe { ffi::lua_iscfunction(self.as_ptr(), index) != 0 }
+    /// Pushes a new table holding the items of `iter` as a sequence, that is at the keys `1, 2,
+    /// ..., n`, and returns its length.
+    ///
+    /// The elements are set without invoking metamethods. Fails, leaving the stack unchanged,
+    /// when an item cannot be pushed or does not use exactly one slot.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// assert_eq!(state.push_iter((0..5).map(|i| i * 10)).unwrap(), 5);
+    ///
+    /// assert_eq!(state.raw_len(-1), 5);
+    /// state.raw_get_index(-1, 3);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(20));
+    /// ```
+    pub fn push_iter<T, I>(&mut self, iter: I) -> Result<usize>
+    where
+        T: Push,
+        I: IntoIterator<Item = T>,
+    {
+        let iter = iter.into_iter();
+        let mark = self.top();
+        self.create_table(iter.size_hint().0.min(i32::MAX as usize) as i32, 0)?;
+        let mut len = 0;
+        for item in iter {
+            match item.push(self) {
+                Ok(1) => {}
+                Ok(n) => {
+                    self.set_top(mark);
+                    let msg = format!("element {} uses {} slots", len + 1, n);
+                    return Err(Error::new(ErrorKind::InvalidInput, msg));
+                }
+                Err(e) => {
+                    self.set_top(mark);
+                    return Err(e);
+                }
+            }
+            len += 1;
+            self.raw_set_index(-2, len as i64);
+        }
+        Ok(len)
     }
 
-    /// Returns `true` if the value at the given `index` is a function (either C or Lua).
-    pub fn is_function(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isfunction(self.as_ptr(), index) != 0 }
+    /// Returns the raw length of the value at the given `index`, that is without invoking the
+    /// `__len` metamethod.
+    ///
+    /// For tables this is a border of the table (see [`§3.4.7`]), which is the length of its
+    /// sequence part, not its total number of keys. For strings this is the string length and for
+    /// userdata the size of the memory block. For other values it is 0.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return {1, 2, 3}, {x = 1, y = 2}, {}").unwrap();
+    /// state.pcall(0, 3, 0).unwrap();
+    ///
+    /// assert_eq!(state.raw_len(1), 3);
+    /// assert_eq!(state.raw_len(2), 0);
+    /// assert_eq!(state.raw_len(3), 0);
+    /// ```
+    ///
+    /// [`§3.4.7`]: https://www.lua.org/manual/5.4/manual.html#3.4.7
+    pub fn raw_len(&self, index: i32) -> usize {
+        unsafe { ffi::lua_rawlen(self.as_ptr(), index) as usize }
     }
 
-    /// Returns `true` if the value at the given `index` is an integer (that is, the value is a
-    /// number and is represented as an integer).
-    pub fn is_integer(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isinteger(self.as_ptr(), index) != 0 }
+    /// Inserts the value on the top of the stack at position `pos` in the sequence at the given
+    /// `index`, shifting up the elements `t[pos], t[pos + 1], ..., t[#t]`, like `table.insert`.
+    /// The value is popped from the stack.
+    ///
+    /// The length of the sequence is its raw length, see [`.raw_len()`]. Fails, leaving the stack
+    /// unchanged, when the value is not a table or `pos` is not in `[1, #t + 1]`. This function
+    /// may trigger metamethods for the "index" and "newindex" events.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return {1, 2, 3}").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    ///
+    /// state.push_integer(10);
+    /// state.table_insert(1, 2).unwrap();
+    ///
+    /// let mut out: Vec<i64> = Vec::new();
+    /// state.extend_vec(1, &mut out).unwrap();
+    /// assert_eq!(out, vec![1, 10, 2, 3]);
+    ///
+    /// state.push_integer(20);
+    /// assert!(state.table_insert(1, 6).is_err());
+    /// assert_eq!(state.top(), 2);
+    ///
+    /// state.push_string("abc").unwrap();
+    /// state.push_integer(30);
+    /// assert!(state.table_insert(3, 1).is_err());
+    /// assert!(state.table_remove(3, 1).is_err());
+    /// assert_eq!(state.top(), 4);
+    /// ```
+    ///
+    /// [`.raw_len()`]: #method.raw_len
+    pub fn table_insert(&mut self, index: i32, pos: i64) -> Result<()> {
+        let index = self.abs_index(index);
+        if !self.is_table(index) {
+            return Err(Error::new(ErrorKind::InvalidData, "value is not a table"));
+        }
+        let end = self.raw_len(index) as i64 + 1;
+        if pos < 1 || pos > end {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("position {} out of bounds [1, {}]", pos, end),
+            ));
+        }
+        for i in (pos + 1..=end).rev() {
+            self.get_index(index, i - 1);
+            self.set_index(index, i);
+        }
+        self.set_index(index, pos);
+        Ok(())
     }
 
-    /// Returns `true` if the value at the given `index` is a light userdata.
-    pub fn is_light_userdata(&self, index: i32) -> bool {
-        unsafe { ffi::lua_islightuserdata(self.as_ptr(), index) != 0 }
+    /// Removes the element at position `pos` from the sequence at the given `index`, shifting down
+    /// the elements `t[pos + 1], t[pos + 2], ..., t[#t]`, like `table.remove`. The removed value
+    /// is pushed onto the stack.
+    ///
+    /// The length of the sequence is its raw length, see [`.raw_len()`]. Fails, leaving the stack
+    /// unchanged, when the value is not a table, or when the sequence is not empty and `pos` is
+    /// not in `[1, #t + 1]`. This function may trigger metamethods for the "index" and "newindex"
+    /// events.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return {1, 2, 3}").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    ///
+    /// state.table_remove(1, 1).unwrap();
+    /// assert_eq!(state.pop_value::<i64>().unwrap(), 1);
+    ///
+    /// let mut out: Vec<i64> = Vec::new();
+    /// state.extend_vec(1, &mut out).unwrap();
+    /// assert_eq!(out, vec![2, 3]);
+    /// assert_eq!(state.raw_len(1), 2);
+    /// ```
+    ///
+    /// [`.raw_len()`]: #method.raw_len
+    pub fn table_remove(&mut self, index: i32, pos: i64) -> Result<()> {
+        let index = self.abs_index(index);
+        if !self.is_table(index) {
+            return Err(Error::new(ErrorKind::InvalidData, "value is not a table"));
+        }
+        let size = self.raw_len(index) as i64;
+        if pos != size && (pos < 1 || pos > size + 1) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("position {} out of bounds [1, {}]", pos, size + 1),
+            ));
+        }
+        self.get_index(index, pos);
+        let mut pos = pos;
+        while pos < size {
+            self.get_index(index, pos + 1);
+            self.set_index(index, pos);
+            pos += 1;
+        }
+        self.push_nil();
+        self.set_index(index, pos);
+        Ok(())
     }
 
-    /// Returns `true` if the value at the given `index` is **nil**.
-    pub fn is_nil(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isnil(self.as_ptr(), index) != 0 }
+    /// Returns `true` if the table at the given `index` has no key at all, in its sequence part or
+    /// otherwise. Returns `false` when the value is not a table.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return {1, 2, 3}, {x = 1, y = 2}, {}, ''").unwrap();
+    /// state.pcall(0, 4, 0).unwrap();
+    ///
+    /// assert!(!state.is_empty_table(1));
+    /// assert!(!state.is_empty_table(2));
+    /// assert!(state.is_empty_table(3));
+    /// assert!(!state.is_empty_table(4));
+    /// assert_eq!(state.top(), 4);
+    /// ```
+    pub fn is_empty_table(&mut self, index: i32) -> bool {
+        if !self.is_table(index) {
+            return false;
+        }
+        let index = self.abs_index(index);
+        self.push_nil();
+        if self.next(index) {
+            self.pop(2);
+            false
+        } else {
+            true
+        }
     }
 
-    /// Returns `true` if the given `index` is not valid.
-    pub fn is_none(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isnone(self.as_ptr(), index) != 0 }
+    /// Returns `true` if the value at the given `index` is a table whose keys are exactly the
+    /// integers `1, 2, ..., n`, that is a sequence with no other key. An empty table is a sequence.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return {1, 2, 3}, {x = 1}, {[1] = 1, [3] = 3}, {}, 'abc'").unwrap();
+    /// state.pcall(0, 5, 0).unwrap();
+    ///
+    /// assert!(state.is_sequence(1));
+    /// assert!(!state.is_sequence(2));
+    /// assert!(!state.is_sequence(3));
+    /// assert!(state.is_sequence(4));
+    /// assert!(!state.is_sequence(5));
+    /// assert_eq!(state.top(), 5);
+    /// ```
+    pub fn is_sequence(&mut self, index: i32) -> bool {
+        if !self.is_table(index) {
+            return false;
+        }
+        let index = self.abs_index(index);
+        let mut count = 0;
+        let mut max = 0;
+        self.push_nil();
+        while self.next(index) {
+            self.pop(1);
+            let key = match self.info(-1).value_type() {
+                Type::Number => self.to_integer::<i64>(-1).filter(|&key| key > 0),
+                _ => None,
+            };
+            match key {
+                Some(key) => {
+                    count += 1;
+                    max = max.max(key);
+                }
+                None => {
+                    self.pop(1);
+                    return false;
+                }
+            }
+        }
+        max == count
     }
 
-    /// Returns `true` if the given `index` is not valid or if the value at this index is **nil**.
-    pub fn is_none_or_nil(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isnone(self.as_ptr(), index) != 0 }
-    }
+    /// Pushes onto the stack a deep copy of the table at the given `index`, that is a new table
+    /// whose nested tables are copies too, so the copy can be mutated without affecting the
+    /// original. Values other than tables are pushed as they are.
+    ///
+    /// A table reachable several times from the original, including through a cycle, is copied
+    /// once and the copy is shared in the same way. Keys are not copied and metatables are shared
+    /// with the original tables. The tables are read and written without invoking metamethods.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("local t = {n = {1, 2}}; t.self = t; return t").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    ///
+    /// state.deep_copy(1).unwrap();
+    /// state.get_field(2, "n").unwrap();
+    /// state.push_integer(10);
+    /// state.set_index(-2, 1);
+    /// state.pop(1);
+    ///
+    /// // the original is unchanged
+    /// state.get_field(1, "n").unwrap();
+    /// state.get_index(-1, 1);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(1));
+    /// state.pop(2);
+    ///
+    /// // the cycle points to the copy
+    /// state.get_field(2, "self").unwrap();
+    /// state.get_field(-1, "n").unwrap();
+    /// state.get_index(-1, 1);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(10));
+    /// state.set_top(0);
+    ///
+    /// // deeply nested tables are copied too
+    /// state.do_string("local t = {} for i = 1, 200000 do t = {t} end return t").unwrap();
+    /// state.deep_copy(1).unwrap();
+    /// assert_eq!(state.top(), 2);
+    /// ```
+    pub fn deep_copy(&mut self, index: i32) -> Result<()> {
+        let index = self.abs_index(index);
+        if !self.is_table(index) {
+            self.push_value(index);
+            return Ok(());
+        }
+        if !self.check_stack(8) {
+            return Err(Error::new(ErrorKind::InvalidInput, "stack overflow"));
+        }
 
-    /// Returns `true` if the value at the given `index` is number or a string convertible to a
-    /// number.
-    pub fn is_number(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isnumber(self.as_ptr(), index) != 0 }
+        // maps the tables already copied to their copy, and lists the tables whose copy is still
+        // to be filled, so nested tables are copied without recursion
+        self.new_table()?;
+        let visited = self.top();
+        let ret = self
+            .new_table()
+            .and_then(|_| self.copy_tables(index, visited));
+        if ret.is_ok() {
+            self.remove(visited);
+            self.remove(visited);
+        } else {
+            self.set_top(visited - 1);
+        }
+        ret
     }
 
-    /// Returns `true` if the value at the given `index` is a string or a number (which is always
-    /// convertible to a string).
-    pub fn is_string(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isstring(self.as_ptr(), index) != 0 }
+    /// Pushes a copy of the table at the absolute `index`, recording the copies in the table at
+    /// the absolute index `visited` and the tables to fill in the sequence just above it.
+    fn copy_tables(&mut self, index: i32, visited: i32) -> Result<()> {
+        let pending = visited + 1;
+        self.copy_of(index, visited)?;
+        loop {
+            let n = self.raw_len(pending) as i64;
+            if n == 0 {
+                return Ok(());
+            }
+            self.raw_get_index(pending, n);
+            self.push_nil();
+            self.raw_set_index(pending, n);
+            let table = self.top();
+            self.push_value(table);
+            self.raw_get(visited);
+            let copy = self.top();
+
+            self.push_nil();
+            while self.next(table) {
+                if self.is_table(-1) {
+                    let value = self.top();
+                    self.copy_of(value, visited)?;
+                    self.replace(value);
+                }
+                self.push_value(-2);
+                self.insert(-2);
+                self.raw_set(copy);
+            }
+            self.pop(2);
+        }
     }
 
-    /// Returns `true` if the value at the given `index` is a table.
-    pub fn is_table(&self, index: i32) -> bool {
-        unsafe { ffi::lua_istable(self.as_ptr(), index) != 0 }
-    }
+    /// Pushes the copy of the table at the absolute `index`, recorded in the table at the absolute
+    /// index `visited`. A new copy, sharing the metatable of the table, is recorded and the table
+    /// added to the tables to fill when the table was not copied yet.
+    fn copy_of(&mut self, index: i32, visited: i32) -> Result<()> {
+        self.push_value(index);
+        if self.raw_get(visited) != Type::Nil {
+            return Ok(());
+        }
+        self.pop(1);
+
+        self.new_table()?;
+        let copy = self.top();
+        self.push_value(index);
+        self.push_value(copy);
+        self.raw_set(visited);
+        if self.get_metatable(index) {
+            self.set_metatable(copy);
+        }
 
-    /// Returns `true` if the value at the given `index` is a thread.
-    pub fn is_thread(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isthread(self.as_ptr(), index) != 0 }
+        let pending = visited + 1;
+        let n = self.raw_len(pending) as i64;
+        self.push_value(index);
+        self.raw_set_index(pending, n + 1);
+        Ok(())
     }
 
-    /// Returns `true` if the value at the given `index` is a userdata (either full or light).
-    pub fn is_userdata(&self, index: i32) -> bool {
-        unsafe { ffi::lua_isuserdata(self.as_ptr(), index) != 0 }
+    /// If the value at the given `index` has a metatable, pushes that metatable onto the stack and
+    /// returns `true`. Otherwise, pushes nothing and returns `false`.
+    pub fn get_metatable(&mut self, index: i32) -> bool {
+        unsafe { ffi::lua_getmetatable(self.as_ptr(), index) != 0 }
     }
 
-    /// Converts the Lua value at the given `index` to a boolean.
+    /// Pops a table or **nil** from the stack and sets that value as the new metatable for the value
+    /// at the given `index` (**nil** means no metatable).
     ///
-    /// Like all tests in Lua, this returns `true` for any Lua value different from `false` and
-    /// **nil**; otherwise it returns `false`.
-    pub fn to_boolean(&self, index: i32) -> bool {
-        unsafe { ffi::lua_toboolean(self.as_ptr(), index) != 0 }
-    }
-
-    /// Converts the Lua value at the given `index` to a byte slice.
+    /// # Examples
     ///
-    /// Returns a reference to the string inside the Lua state.
-    pub fn as_bytes<'a>(&'a self, index: i32) -> &'a [u8] {
-        unsafe {
-            let mut len = 0;
-            let ptr = ffi::lua_tolstring(self.as_ptr(), index, &mut len);
-            let data = ptr as *const u8;
-            std::slice::from_raw_parts(data, len)
-        }
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.new_table().unwrap();
+    ///
+    /// // fields missing from the table are looked up in the `__index` table
+    /// state.new_table().unwrap();
+    /// state.new_table().unwrap();
+    /// state.push_string("base").unwrap();
+    /// state.set_field(-2, "kind").unwrap();
+    /// state.set_field(-2, "__index").unwrap();
+    /// state.set_metatable(-2);
+    ///
+    /// state.get_field(-1, "kind").unwrap();
+    /// assert_eq!(state.as_bytes(-1), b"base");
+    /// state.pop(1);
+    ///
+    /// assert!(state.get_metatable(-1));
+    /// assert!(state.is_table(-1));
+    /// ```
+    pub fn set_metatable(&mut self, index: i32) {
+        unsafe { ffi::lua_setmetatable(self.as_ptr(), index) }
     }
 
-    /// Converts the Lua value at the given `index` to a signed integer.
-    pub fn to_integer<T: num_traits::NumCast>(&self, index: i32) -> Option<T> {
-        let mut isnum = 0;
-        let n = unsafe { ffi::lua_tointegerx(self.as_ptr(), index, &mut isnum) };
-        if isnum == 0 {
-            None
-        } else {
-            num_traits::cast(n)
+    /// Protects the metatable of the value at the given `index` by setting its `__metatable` field
+    /// to `protection`.
+    ///
+    /// Once protected, `getmetatable` returns `protection` instead of the metatable and
+    /// `setmetatable` raises an error, so scripts can neither inspect nor replace it.
+    ///
+    /// Returns an error when the value has no metatable.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    ///
+    /// state.new_userdata(0, 0).unwrap();
+    /// state.new_table().unwrap();
+    /// state.set_metatable(-2);
+    /// state.protect_metatable(-1, "locked".as_bytes()).unwrap();
+    /// state.set_global("handle").unwrap();
+    ///
+    /// state.new_table().unwrap();
+    /// state.new_table().unwrap();
+    /// state.set_metatable(-2);
+    /// state.protect_metatable(-1, "locked".as_bytes()).unwrap();
+    /// state.set_global("object").unwrap();
+    ///
+    /// state.load_string(r#"
+    ///     assert(getmetatable(handle) == "locked")
+    ///     setmetatable(object, {})
+    /// "#).unwrap();
+    /// let error = state.pcall(0, 0, 0).unwrap_err();
+    /// assert!(error.to_string().contains("cannot change a protected metatable"));
+    /// ```
+    pub fn protect_metatable<T: Push>(&mut self, index: i32, protection: T) -> Result<()> {
+        let mut state = StackGuard::new(self);
+        if !state.get_metatable(index) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "value has no metatable to protect",
+            ));
         }
+        protection.push(&mut state)?;
+        state.set_field(-2, "__metatable")
     }
 
-    /// Converts the Lua value at the given `index` to a float.
-    pub fn to_number<T: num_traits::NumCast>(&self, index: i32) -> Option<T> {
-        let mut isnum = 0;
-        let n = unsafe { ffi::lua_tonumberx(self.as_ptr(), index, &mut isnum) };
-        if isnum == 0 {
-            None
-        } else {
-            num_traits::cast(n)
-        }
+    /// Pushes onto the stack the value `t[k]`, where `t` is the value at the given `index`, like
+    /// [`.get_field()`], but the `key` can contain any binary data, including embedded zeros.
+    ///
+    /// Returns the type of the pushed value.
+    ///
+    /// [`.get_field()`]: State::get_field
+    pub fn get_field_bytes<K: AsRef<[u8]>>(&mut self, index: i32, key: K) -> Result<Type> {
+        let index = self.abs_index(index);
+        self.push_string(key)?;
+        Ok(self.get_table(index))
     }
 
-    /// If the value at the given `index` is a full userdata, returns its memory-block address. If
-    /// the value is a light userdata, returns its value (a pointer). Otherwise, returns NULL.
-    pub fn to_userdata(&self, index: i32) -> *mut c_void {
-        unsafe { ffi::lua_touserdata(self.as_ptr(), index) }
+    /// Does the equivalent to `t[k] = v`, where `t` is the value at the given `index` and `v` is
+    /// the value on the top of the stack, like [`.set_field()`], but the `key` can contain any
+    /// binary data, including embedded zeros.
+    ///
+    /// This function pops the value from the stack.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.new_table().unwrap();
+    /// state.push_integer(42);
+    /// state.set_field_bytes(-2, b"a\0b").unwrap();
+    ///
+    /// state.get_field_bytes(-1, b"a\0b").unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(42));
+    /// state.pop(1);
+    ///
+    /// state.get_field_bytes(-1, b"a").unwrap();
+    /// assert!(state.is_nil(-1));
+    /// ```
+    ///
+    /// [`.set_field()`]: State::set_field
+    pub fn set_field_bytes<K: AsRef<[u8]>>(&mut self, index: i32, key: K) -> Result<()> {
+        let index = self.abs_index(index);
+        self.push_string(key)?;
+        self.insert(-2);
+        self.set_table(index);
+        Ok(())
     }
 
-    /// Converts the Lua value at the given `index` to a C string.
-    pub fn as_c_str<'a>(&'a self, index: i32) -> &'a CStr {
-        unsafe { CStr::from_ptr(ffi::lua_tostring(self.as_ptr(), index)) }
+    /// Pushes onto the stack the value `t[n]`, where `t` is the value at the given `index`. As in
+    /// Lua, this function may trigger a metamethod for the "index" event (see [`§2.4`]).
+    ///
+    /// Returns the type of the pushed value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.new_table().unwrap();
+    /// for n in 1..=3 {
+    ///     state.push_integer(n * 10);
+    ///     state.set_index(-2, n);
+    /// }
+    ///
+    /// state.get_index(-1, 2);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(20));
+    /// ```
+    ///
+    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
+    pub fn get_index(&mut self, index: i32, n: i64) -> Type {
+        unsafe { ffi::lua_geti(self.as_ptr(), index, n) }.into()
     }
 
-    /// Returns the [`Info`] of the stack element at the given `index`.
-    pub fn info(&self, idx: i32) -> Info<'_> {
-        unsafe {
-            let state = self.as_ptr();
-
-            let tp = ffi::lua_type(state, idx);
-
-            let name = CStr::from_ptr(ffi::lua_typename(state, tp));
-
-            Info { idx, tp, name }
-        }
+    /// Does the equivalent to `t[n] = v`, where `t` is the value at the given `index` and `v` is
+    /// the value on the top of the stack.
+    ///
+    /// This function pops the value from the stack. As in Lua, this function may trigger a
+    /// metamethod for the "newindex" event (see [`§2.4`]).
+    ///
+    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
+    pub fn set_index(&mut self, index: i32, n: i64) {
+        unsafe { ffi::lua_seti(self.as_ptr(), index, n) }
     }
 
-    /// Returns the index of the top element in the stack.
+    /// Pushes onto the stack the value `t[n]`, where `t` is the table at the given `index`, like
+    /// [`.get_index()`] but without invoking metamethods.
     ///
-    /// Because indices start at 1, this result is equal to the number of elements in the stack; in
-    /// particular, 0 means an empty stack.
-    pub fn top(&self) -> i32 {
-        unsafe { ffi::lua_gettop(self.as_ptr()) }
+    /// Returns the type of the pushed value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.load_string(r#"
+    ///     return setmetatable({}, {
+    ///         __index = function() return 99 end,
+    ///         __newindex = function() end,
+    ///     })
+    /// "#).unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    ///
+    /// state.push_integer(1);
+    /// state.raw_set_index(-2, 1);
+    /// state.raw_get_index(-1, 1);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(1));
+    /// state.pop(1);
+    ///
+    /// state.raw_get_index(-1, 2);
+    /// assert!(state.is_nil(-1));
+    /// state.pop(1);
+    ///
+    /// state.get_index(-1, 2);
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(99));
+    /// ```
+    ///
+    /// [`.get_index()`]: State::get_index
+    pub fn raw_get_index(&mut self, index: i32, n: i64) -> Type {
+        unsafe { ffi::lua_rawgeti(self.as_ptr(), index, n) }.into()
     }
 
-    /// Accepts any `index`, or 0, and sets the stack top to this `index`. If the new top is greater
-    /// than the old one, then the new elements are filled with **nil**. If `index` is 0, then all
-    /// stack elements are removed.
+    /// Does the equivalent of `t[n] = v`, where `t` is the table at the given `index` and `v` is
+    /// the value on the top of the stack, like [`.set_index()`] but without invoking metamethods.
     ///
-    /// This function can run arbitrary code when removing an index marked as to-be-closed from the
-    /// stack.
-    pub fn set_top(&mut self, index: i32) {
-        unsafe { ffi::lua_settop(self.as_ptr(), index) }
+    /// This function pops the value from the stack.
+    ///
+    /// [`.set_index()`]: State::set_index
+    pub fn raw_set_index(&mut self, index: i32, n: i64) {
+        unsafe { ffi::lua_rawseti(self.as_ptr(), index, n) }
     }
 
-    /// Pops n elements from the stack.
+    /// Pushes onto the stack the value `t[k]`, where `t` is the table at the given `index` and `k`
+    /// is the value on the top of the stack, like [`.get_table()`] but without invoking
+    /// metamethods.
+    ///
+    /// This methods pops the key from the stack, pushing the resulting value in its place. Returns
+    /// the type of the pushed value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    /// state.load_string(r#"
+    ///     return setmetatable({}, {
+    ///         __index = function() return "meta" end,
+    ///         __newindex = function() error("read-only") end,
+    ///     })
+    /// "#).unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
     ///
-    /// This can run arbitrary code when removing an index marked as to-be-closed from the stack.
-    pub fn pop(&mut self, n: i32) {
-        unsafe { ffi::lua_pop(self.as_ptr(), n) }
+    /// state.push_string("key").unwrap();
+    /// state.push_string("raw").unwrap();
+    /// state.raw_set(-3);
+    ///
+    /// state.push_string("key").unwrap();
+    /// assert_eq!(state.raw_get(-2), lua::Type::String);
+    /// assert_eq!(state.as_bytes(-1), b"raw");
+    /// state.pop(1);
+    ///
+    /// state.push_string("missing").unwrap();
+    /// assert_eq!(state.raw_get(-2), lua::Type::Nil);
+    /// state.pop(1);
+    ///
+    /// state.get_field(-1, "missing").unwrap();
+    /// assert_eq!(state.as_bytes(-1), b"meta");
+    /// ```
+    ///
+    /// [`.get_table()`]: State::get_table
+    pub fn raw_get(&mut self, index: i32) -> Type {
+        unsafe { ffi::lua_rawget(self.as_ptr(), index) }.into()
     }
 
-    /// Pushes a copy of the element at the given `index` onto the stack.
-    pub fn push_value(&mut self, index: i32) {
-        unsafe { ffi::lua_pushvalue(self.as_ptr(), index) }
+    /// Does the equivalent of `t[k] = v`, where `t` is the table at the given `index`, `v` is the
+    /// value on the top of the stack, and `k` is the value just below the top, like
+    /// [`.set_table()`] but without invoking metamethods.
+    ///
+    /// This function pops both the key and the value from the stack.
+    ///
+    /// [`.set_table()`]: State::set_table
+    pub fn raw_set(&mut self, index: i32) {
+        unsafe { ffi::lua_rawset(self.as_ptr(), index) }
     }
 
-    /// Rotates the stack elements between the valid index idx and the top of the stack.
+    /// Pops a key from the stack, and pushes a key–value pair from the table at the given `index`,
+    /// the "next" pair after the given key. If there are no more elements in the table, then
+    /// returns `false` and pushes nothing.
     ///
-    /// The elements are rotated `n` positions in the direction of the top, for a positive `n`, or
-    /// `-n` positions in the direction of the bottom, for a negative `n`. The absolute value of `n`
-    /// must not be greater than the size of the slice being rotated.
+    /// A traversal starts by pushing **nil** as key. While traversing a table, avoid converting the
+    /// key in place (for instance with [`.as_bytes()`] on a number), unless you know that the key
+    /// is actually a string, as this confuses the next call to `next`. The value of existing
+    /// fields can be modified or cleared during the traversal, but new fields must not be added.
     ///
-    /// ## Pseudo-index support
+    /// # Examples
     ///
-    /// This function cannot be called with a pseudo-index, because a pseudo-index is not an actual
-    /// stack position.
-    pub fn rotate(&mut self, index: i32, n: i32) {
-        unsafe { ffi::lua_rotate(self.as_ptr(), index, n) }
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return {a = 1, b = 2, c = 3}").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    ///
+    /// let mut sum = 0;
+    /// state.push_nil(); // first key
+    /// while state.next(-2) {
+    ///     // key at index -2 and value at index -1
+    ///     sum += state.to_integer::<i64>(-1).unwrap();
+    ///     state.pop(1); // keep the key for the next iteration
+    /// }
+    /// assert_eq!(sum, 6);
+    /// assert_eq!(state.top(), 1);
+    /// ```
+    ///
+    /// [`.as_bytes()`]: State::as_bytes
+    pub fn next(&mut self, index: i32) -> bool {
+        unsafe { ffi::lua_next(self.as_ptr(), index) != 0 }
     }
 
-    /// Removes the element at the given valid `index`, shifting down the elements above this `index`
-    /// to fill the gap.
+    /// Creates a new empty table and pushes it onto the stack.
     ///
-    /// ## Pseudo-index support
+    /// Returns an error, leaving the stack unchanged, when Lua fails to allocate the table.
+    pub fn new_table(&mut self) -> Result<()> {
+        self.protect(|state| unsafe { ffi::lua_newtable(state.as_ptr()) })
+    }
+
+    /// Creates a new empty table and pushes it onto the stack. Parameter `narr` is a hint for how
+    /// many elements the table will have as a sequence; parameter `nrec` is a hint for how many
+    /// other elements the table will have. Lua may use these hints to preallocate memory for the
+    /// new table. This preallocation may help performance when you know in advance how many elements
+    /// the table will have. Otherwise you can use [`.new_table()`](State::new_table).
     ///
-    /// This function cannot be called with a pseudo-index, because a pseudo-index is not an actual
-    /// stack position.
-    pub fn remove(&mut self, index: i32) {
-        unsafe { ffi::lua_remove(self.as_ptr(), index) }
+    /// Returns an error, leaving the stack unchanged, when Lua fails to allocate the table.
+    pub fn create_table(&mut self, narr: i32, nrec: i32) -> Result<()> {
+        self.protect(|state| unsafe { ffi::lua_createtable(state.as_ptr(), narr, nrec) })
     }
 
-    /// Moves the top element into the given valid `index`, shifting up the elements above this
-    /// `index` to open space.
+    /// This function creates and pushes on the stack a new full userdata, with `nuvalue` associated
+    /// Lua values, called user values, plus an associated block of raw memory with `size` bytes.
+    /// (The user values can be set and read with [`.set_user_value()`] and [`.get_user_value()`].)
     ///
-    /// ## Pseudo-index support
+    /// The function returns the address of the block of memory. Lua ensures that this address is
+    /// valid as long as the corresponding userdata is alive (see [`§2.5`]). Moreover, if the
+    /// userdata is marked for finalization (see [`§2.5.3`]), its address is valid at least until the
+    /// call to its finalizer.
     ///
-    /// This function cannot be called with a pseudo-index, because a pseudo-index is not an actual
-    /// stack position.
-    pub fn insert(&mut self, index: i32) {
-        unsafe { ffi::lua_insert(self.as_ptr(), index) }
+    /// The block of memory is only aligned for the basic C types, so storing a Rust value with a
+    /// larger alignment requirement in it is undefined behavior; use [`.push_userdata()`] for
+    /// such values.
+    ///
+    /// Returns an error, leaving the stack unchanged, when Lua fails to allocate the userdata.
+    ///
+    /// [`§2.5`]: https://www.lua.org/manual/5.4/manual.html#2.5
+    /// [`§2.5.3`]: https://www.lua.org/manual/5.4/manual.html#2.5.3
+    /// [`.push_userdata()`]: State::push_userdata
+    /// [`.set_user_value()`]: State::set_user_value
+    /// [`.get_user_value()`]: State::get_user_value
+    pub fn new_userdata(&mut self, size: usize, nuvalue: i32) -> Result<*mut c_void> {
+        let mut block = ptr::null_mut();
+        self.protect(|state| {
+            block = unsafe { ffi::lua_newuserdatauv(state.as_ptr(), size, nuvalue) };
+        })?;
+        Ok(block)
     }
 
-    /// Moves the top element into the given valid `index` without shifting any element (therefore
-    /// replacing the value at that given `index`), and then pops the top element.
-    pub fn replace(&mut self, index: i32) {
-        unsafe { ffi::lua_replace(self.as_ptr(), index) }
+    /// Pops a value from the stack and sets it as the new `n`-th user value associated to the full
+    /// userdata at the given `udindex`. Returns `false` if the userdata does not have that value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{State, Type};
+    ///
+    /// let mut state = State::new();
+    /// state.new_userdata(8, 1).unwrap();
+    ///
+    /// // attach a method cache to the userdata
+    /// state.new_table().unwrap();
+    /// state.push_integer(42);
+    /// state.set_field(-2, "answer").unwrap();
+    /// assert!(state.set_user_value(-2, 1));
+    /// assert_eq!(state.top(), 1);
+    ///
+    /// assert_eq!(state.get_user_value(-1, 1), Type::Table);
+    /// state.get_field(-1, "answer").unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(42));
+    /// state.pop(2);
+    ///
+    /// // the userdata has a single user value
+    /// state.push_integer(1);
+    /// assert!(!state.set_user_value(-2, 2));
+    /// assert_eq!(state.get_user_value(-1, 2), Type::None);
+    /// assert!(state.is_nil(-1));
+    /// ```
+    pub fn set_user_value(&mut self, udindex: i32, n: i32) -> bool {
+        unsafe { ffi::lua_setiuservalue(self.as_ptr(), udindex, n) != 0 }
     }
 
-    /// Copies the element at index `fromidx` into the valid index `toidx`, replacing the value at
-    /// that position. Values at other positions are not affected.
-    pub fn copy(&mut self, fromidx: i32, toidx: i32) {
-        unsafe { ffi::lua_copy(self.as_ptr(), fromidx, toidx) }
+    /// Pushes onto the stack the `n`-th user value associated with the full userdata at the given
+    /// `udindex` and returns the type of the pushed value.
+    ///
+    /// If the userdata does not have that value, pushes **nil** and returns [`Type::None`].
+    pub fn get_user_value(&mut self, udindex: i32, n: i32) -> Type {
+        unsafe { ffi::lua_getiuservalue(self.as_ptr(), udindex, n) }.into()
     }
 
-    /// Returns an iterator over the stack (from bottom to top).
-    pub fn iter(&self) -> Iter<'_> {
-        let top = self.top();
-        Iter {
-            state: self,
-            n: 1,
-            top,
+    /// Moves `value` into a new full userdata pushed onto the stack and returns the address of the
+    /// value, which is valid as long as the userdata is alive.
+    ///
+    /// Unlike [`.new_userdata()`], the value is correctly aligned whatever its alignment
+    /// requirement: when it exceeds the alignment of the memory blocks of Lua, the block is
+    /// over-allocated and the value stored at an aligned offset. When the value needs to be
+    /// dropped, the userdata gets a metatable with a `__gc` metamethod dropping it. That metatable
+    /// is locked, `getmetatable` returns `false` for the userdata, and the metamethod drops the
+    /// value at most once, even when called by hand through the `debug` library.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use std::rc::Rc;
+    ///
+    /// use lua::State;
+    ///
+    /// #[repr(align(64))]
+    /// struct Aligned([u8; 64]);
+    ///
+    /// let mut state = State::new();
+    /// let ptr = state.push_userdata(Aligned([7; 64])).unwrap();
+    /// assert_eq!(ptr as usize % 64, 0);
+    /// assert_eq!(unsafe { (*ptr).0[63] }, 7);
+    /// assert!(state.is_userdata(-1));
+    ///
+    /// let counter = Rc::new(());
+    /// state.open_libs();
+    /// state.push_userdata(Rc::clone(&counter)).unwrap();
+    /// state.set_global("ud").unwrap();
+    /// state.push_userdata(String::from("other")).unwrap();
+    /// state.set_global("other").unwrap();
+    /// state.do_string(r#"
+    ///     assert(getmetatable(ud) == false)
+    ///     local gc = debug.getmetatable(ud).__gc
+    ///     gc(other)
+    ///     gc(ud)
+    ///     gc(ud)
+    /// "#).unwrap();
+    /// assert_eq!(Rc::strong_count(&counter), 1);
+    ///
+    /// drop(state);
+    /// assert_eq!(Rc::strong_count(&counter), 1);
+    /// ```
+    ///
+    /// [`.new_userdata()`]: State::new_userdata
+    pub fn push_userdata<T: 'static>(&mut self, value: T) -> Result<*mut T> {
+        let size = mem::size_of::<T>() + userdata_padding::<T>();
+        if !mem::needs_drop::<T>() {
+            let ptr = align_userdata::<T>(self.new_userdata(size, 0)?);
+            unsafe { ptr::write(ptr, value) };
+            return Ok(ptr);
         }
+
+        // allocate the metatable first, so that the value is never left without its finalizer;
+        // the metatable is locked so that `getmetatable` and `setmetatable` cannot reach it
+        self.protect(|state| unsafe {
+            ffi::lua_createtable(state.as_ptr(), 0, 2);
+            ffi::lua_pushvalue(state.as_ptr(), -1);
+            ffi::lua_pushcclosure(state.as_ptr(), drop_userdata::<T>, 1);
+            ffi::lua_setfield(state.as_ptr(), -2, b"__gc\0".as_ptr() as *const i8);
+            ffi::lua_pushboolean(state.as_ptr(), 0);
+            ffi::lua_setfield(state.as_ptr(), -2, b"__metatable\0".as_ptr() as *const i8);
+        })?;
+        let block = match self.new_userdata(size, 0) {
+            Ok(block) => block,
+            Err(e) => {
+                self.pop(1);
+                return Err(e);
+            }
+        };
+        let ptr = align_userdata::<T>(block);
+        unsafe { ptr::write(ptr, value) };
+        self.insert(-2);
+        self.set_metatable(-2);
+        Ok(ptr)
     }
 
-    /// Creates an iterator which dumps the values on a Lua stack from bottom to top.
-    pub fn dump(&self) -> Dump<'_> {
-        Dump { iter: self.iter() }
+    /// Creates and returns a reference, in the registry, for the object on the top of the stack
+    /// (and pops the object).
+    ///
+    /// A reference is a unique integer key. As long as you do not manually add integer keys into
+    /// the registry, this function ensures the uniqueness of the key it returns. You can retrieve
+    /// an object referred by reference `r` by calling [`.push_reference(r)`]. The function
+    /// [`.unreference()`] frees a reference.
+    ///
+    /// If the object on the top of the stack is **nil**, returns the constant `LUA_REFNIL`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.new_table().unwrap();
+    /// state.push_integer(42);
+    /// state.set_field(-2, "answer").unwrap();
+    ///
+    /// let r = state.reference();
+    /// state.set_top(0);
+    ///
+    /// assert_eq!(state.push_reference(r), lua::Type::Table);
+    /// state.get_field(-1, "answer").unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(42));
+    ///
+    /// state.unreference(r);
+    /// ```
+    ///
+    /// [`.push_reference(r)`]: State::push_reference
+    /// [`.unreference()`]: State::unreference
+    pub fn reference(&mut self) -> i32 {
+        unsafe { ffi::luaL_ref(self.as_ptr(), ffi::LUA_REGISTRYINDEX) }
     }
 
-    /// Pushes onto the stack the value of the global name. Returns the type of that value.
-    pub fn get_global<T: Into<Vec<u8>>>(&mut self, name: T) -> Result<i32> {
-        let name = CString::new(name)?;
-        Ok(unsafe { ffi::lua_getglobal(self.as_ptr(), name.as_ptr()) })
+    /// Pushes onto the stack the value referred by reference `r` in the registry (see
+    /// [`.reference()`]). Returns the type of the pushed value.
+    ///
+    /// [`.reference()`]: State::reference
+    pub fn push_reference(&mut self, r: i32) -> Type {
+        self.raw_get_index(ffi::LUA_REGISTRYINDEX, r as i64)
     }
 
-    pub fn set_global<T: Into<Vec<u8>>>(&mut self, name: T) -> Result<()> {
-        let name = CString::new(name)?;
-        Ok(unsafe { ffi::lua_setglobal(self.as_ptr(), name.as_ptr()) })
+    /// Releases the reference `r` from the registry (see [`.reference()`]). The entry is removed
+    /// from the registry, so that the referred object can be collected. The reference `r` is also
+    /// freed to be used again.
+    ///
+    /// [`.reference()`]: State::reference
+    pub fn unreference(&mut self, r: i32) {
+        unsafe { ffi::luaL_unref(self.as_ptr(), ffi::LUA_REGISTRYINDEX, r) }
     }
 
-    /// Pushes onto the stack the value `t[k]`, where `t` is the value at the given index and `k` is
-    /// the value on the top of the stack.
+    /// Interns the string `s`, that is creates a reference in the registry (see [`.reference()`])
+    /// for the Lua string `s`, and returns the reference.
     ///
-    /// This methods pops the key from the stack, pushing the resulting value in its place. As in
-    /// Lua, this function may trigger a metamethod for the "index" event (see [`§2.4`]).
+    /// Hot-path code can push the interned string again with [`.push_reference(r)`], which avoids
+    /// copying and hashing the string at each use. Free the reference with [`.unreference()`]
+    /// when it is no longer needed.
     ///
-    /// Returns the type of the pushed value.
+    /// # Examples
     ///
-    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
-    pub fn get_table(&mut self, index: i32) -> i32 {
-        unsafe { ffi::lua_gettable(self.as_ptr(), index) }
-    }
-
-    /// Pushes onto the stack the value `t[k]`, where `t` is the value at the given `index`. As in
-    /// Lua, this function may trigger a metamethod for the "index" event (see [`§2.4`]).
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{State, Type};
     ///
-    /// Returns the type of the pushed value.
+    /// let mut state = State::new();
+    /// state.load_string("return {{name = 'a'}, {name = 'b'}, {name = 'c'}}").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
     ///
-    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
-    pub fn get_field<T: Into<Vec<u8>>>(&mut self, index: i32, key: T) -> Result<i32> {
-        let key = CString::new(key)?;
-        Ok(unsafe { ffi::lua_getfield(self.as_ptr(), index, key.as_ptr()) })
-    }
-
-    /// Does the equivalent to `t[k] = v`, where `t` is the value at the given index, `v` is the
-    /// value on the top of the stack, and `k` is the value just below the top.
+    /// let name = state.intern("name").unwrap();
+    /// let mut names = Vec::new();
+    /// for i in 1..=3 {
+    ///     state.get_index(1, i);
+    ///     state.push_reference(name);
+    ///     assert_eq!(state.get_table(-2), Type::String);
+    ///     names.push(state.pop_value::<String>().unwrap());
+    ///     state.pop(1);
+    /// }
+    /// assert_eq!(names, vec!["a", "b", "c"]);
     ///
-    /// This function pops both the key and the value from the stack. As in Lua, this function may
-    /// trigger a metamethod for the "newindex" event (see [`§2.4`]).
+    /// state.unreference(name);
+    /// ```
     ///
-    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
-    pub fn set_table(&mut self, index: i32) {
-        unsafe { ffi::lua_settable(self.as_ptr(), index) }
+    /// [`.reference()`]: State::reference
+    /// [`.push_reference(r)`]: State::push_reference
+    /// [`.unreference()`]: State::unreference
+    pub fn intern<S: AsRef<[u8]>>(&mut self, s: S) -> Result<i32> {
+        self.push_string(s)?;
+        Ok(self.reference())
     }
 
-    /// Does the equivalent to `t[k] = v`, where `t` is the value at the given `index` and `v` is
-    /// the value on the top of the stack.
+    /// Switches the garbage collector to the given `mode` with its parameters.
     ///
-    /// This function pops the value from the stack. As in Lua, this function may trigger a
-    /// metamethod for the "newindex" event (see [`§2.4`]).
+    /// # Examples
     ///
-    /// [`§2.4`]: https://www.lua.org/manual/5.4/manual.html#2.4
-    pub fn set_field<T: Into<Vec<u8>>>(&mut self, index: i32, key: T) -> Result<()> {
-        let key = CString::new(key)?;
-        Ok(unsafe { ffi::lua_setfield(self.as_ptr(), index, key.as_ptr()) })
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::GcMode;
+    /// use lua::State;
+    ///
+    /// let collects = r#"
+    ///     local before = collectgarbage("count")
+    ///     local garbage = {}
+    ///     for i = 1, 10000 do garbage[i] = {i} end
+    ///     garbage = nil
+    ///     collectgarbage()
+    ///     return collectgarbage("count") < before + 64
+    /// "#;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    ///
+    /// state.set_gc_mode(GcMode::Generational { minormul: 20, majormul: 100 });
+    /// state.do_string(collects).unwrap();
+    /// assert!(state.to_boolean(-1));
+    ///
+    /// state.set_gc_mode(GcMode::Incremental { pause: 200, stepmul: 100, stepsize: 0 });
+    /// state.do_string(collects).unwrap();
+    /// assert!(state.to_boolean(-1));
+    /// ```
+    pub fn set_gc_mode(&mut self, mode: GcMode) {
+        unsafe {
+            match mode {
+                GcMode::Incremental {
+                    pause,
+                    stepmul,
+                    stepsize,
+                } => ffi::lua_gc(self.as_ptr(), ffi::LUA_GCINC, pause, stepmul, stepsize),
+                GcMode::Generational { minormul, majormul } => {
+                    ffi::lua_gc(self.as_ptr(), ffi::LUA_GCGEN, minormul, majormul)
+                }
+            };
+        }
     }
 
-    /// Creates a new empty table and pushes it onto the stack.
-    pub fn new_table(&mut self) {
-        unsafe { ffi::lua_newtable(self.as_ptr()) }
+    /// Returns the memory usage tracked by the allocator of the state, or `None` when the state
+    /// was not created by [`new`](#method.new) or [`with_limit`](#method.with_limit).
+    fn memory_info(&self) -> Option<alloc::MemoryInfo> {
+        let mut ud = ptr::null_mut();
+        unsafe {
+            let allocf = ffi::lua_getallocf(self.as_ptr(), &mut ud);
+            if allocf as usize == alloc::alloc as ffi::lua_Alloc as usize && !ud.is_null() {
+                Some(*(ud as *const alloc::MemoryInfo))
+            } else {
+                None
+            }
+        }
     }
 
-    /// Creates a new empty table and pushes it onto the stack. Parameter `narr` is a hint for how
-    /// many elements the table will have as a sequence; parameter `nrec` is a hint for how many
-    /// other elements the table will have. Lua may use these hints to preallocate memory for the
-    /// new table. This preallocation may help performance when you know in advance how many elements
-    /// the table will have. Otherwise you can use [`.new_table()`](State::new_table).
-    pub fn create_table(&mut self, narr: i32, nrec: i32) {
-        unsafe { ffi::lua_createtable(self.as_ptr(), narr, nrec) }
+    /// Returns the number of bytes currently allocated by the state.
+    ///
+    /// Returns 0 for a state using a custom [`LuaAllocator`](crate::LuaAllocator).
+    pub fn memory_used(&self) -> usize {
+        self.memory_info().map_or(0, |info| info.used())
     }
 
-    /// This function creates and pushes on the stack a new full userdata, with `nuvalue` associated
-    /// Lua values, called user values, plus an associated block of raw memory with `size` bytes.
-    /// (The user values can be set and read with the functions lua_setiuservalue and lua_getiuservalue.)
+    /// Returns the highest number of bytes allocated at once by the state since it was created,
+    /// which helps sizing the memory limit given to [`with_limit`](#method.with_limit).
     ///
-    /// The function returns the address of the block of memory. Lua ensures that this address is
-    /// valid as long as the corresponding userdata is alive (see [`§2.5`]). Moreover, if the
-    /// userdata is marked for finalization (see [`§2.5.3`]), its address is valid at least until the
-    /// call to its finalizer.
+    /// Returns 0 for a state using a custom [`LuaAllocator`](crate::LuaAllocator).
     ///
-    /// [`§2.5`]: https://www.lua.org/manual/5.4/manual.html#2.5
-    /// [`§2.5.3`]: https://www.lua.org/manual/5.4/manual.html#2.5.3
-    pub fn new_userdata(&mut self, size: usize, nuvalue: i32) -> *mut c_void {
-        unsafe { ffi::lua_newuserdatauv(self.as_ptr(), size, nuvalue) }
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.open_libs();
+    ///
+    /// state.do_string("t = {} for i = 1, 10000 do t[i] = tostring(i) end").unwrap();
+    /// let used = state.memory_used();
+    /// assert!(state.memory_peak() >= used);
+    ///
+    /// state.do_string("t = nil collectgarbage()").unwrap();
+    /// assert!(state.memory_used() < used);
+    /// assert!(state.memory_peak() > state.memory_used());
+    /// ```
+    pub fn memory_peak(&self) -> usize {
+        self.memory_info().map_or(0, |info| info.peak())
     }
 
     /// Returns the pseudo-index that represents the `i`-th upvalue of the running function (see
@@ -782,6 +4693,38 @@ impl State {
 
     /// Checks whether the function argument `arg` is an integer (or can be converted to an integer)
     /// and returns this integer.
+    ///
+    /// Otherwise raises a Lua error with the standard message of a bad argument, e.g. `bad
+    /// argument #1 to 'f' (number expected, got string)`. As for [`.raise_error()`], the
+    /// underlying C function does a long jump and this method never returns then.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn double(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     let n = state.check_integer(1);
+    ///     state.push_integer(n * 2);
+    ///     1
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.push_cfunction(double);
+    /// state.set_global("double").unwrap();
+    ///
+    /// state.load_string("return double(21)").unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(42));
+    ///
+    /// state.load_string("return double('x')").unwrap();
+    /// let e = state.pcall(0, 1, 0).unwrap_err();
+    /// assert!(e.to_string().contains("bad argument #1 to 'double' (number expected, got string)"));
+    /// ```
+    ///
+    /// [`.raise_error()`]: State::raise_error
     pub fn check_integer(&self, arg: i32) -> i64 {
         unsafe { ffi::luaL_checkinteger(self.as_ptr(), arg) }
     }
@@ -800,6 +4743,61 @@ impl State {
     pub fn check_number(&self, arg: i32) -> f64 {
         unsafe { ffi::luaL_checknumber(self.as_ptr(), arg) }
     }
+
+    /// Checks whether the function argument `arg` is a table.
+    pub fn check_table(&self, arg: i32) {
+        unsafe { ffi::luaL_checktype(self.as_ptr(), arg, ffi::LUA_TTABLE) }
+    }
+
+    /// If the function argument `arg` is an integer (or can be converted to an integer), returns
+    /// this integer. If this argument is absent or is **nil**, returns `default`. Otherwise,
+    /// raises a Lua error, as [`.check_integer()`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn repeat(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     let n = state.opt_integer(1, 2);
+    ///     let s = String::from_utf8_lossy(state.opt_string(2, "ab")).repeat(n as usize);
+    ///     state.push_string(s).unwrap();
+    ///     1
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// state.push_cfunction(repeat);
+    /// state.set_global("rep").unwrap();
+    ///
+    /// state.load_string("return rep(), rep(nil, nil), rep(3, 'x')").unwrap();
+    /// state.pcall(0, 3, 0).unwrap();
+    /// assert_eq!(state.as_bytes(1), b"abab");
+    /// assert_eq!(state.as_bytes(2), b"abab");
+    /// assert_eq!(state.as_bytes(3), b"xxx");
+    ///
+    /// state.load_string("return rep({})").unwrap();
+    /// let e = state.pcall(0, 1, 0).unwrap_err();
+    /// assert!(e.to_string().contains("bad argument #1 to 'rep' (number expected, got table)"));
+    /// ```
+    ///
+    /// [`.check_integer()`]: State::check_integer
+    pub fn opt_integer(&self, arg: i32, default: i64) -> i64 {
+        unsafe { ffi::luaL_optinteger(self.as_ptr(), arg, default) }
+    }
+
+    /// If the function argument `arg` is a string, returns this string. If this argument is absent
+    /// or is **nil**, returns `default`. Otherwise, raises a Lua error, as [`.check_string()`].
+    ///
+    /// [`.check_string()`]: State::check_string
+    pub fn opt_string<'a>(&'a self, arg: i32, default: &'a str) -> &'a [u8] {
+        if self.is_none_or_nil(arg) {
+            default.as_bytes()
+        } else {
+            self.check_string(arg)
+        }
+    }
 }
 
 impl Default for State {
@@ -817,7 +4815,10 @@ impl Drop for State {
         unsafe {
             // SAFETY: This unsafety is ok becuase while this `State` is alive
             // we're guaranteed that the inner pointer was not freed before.
-            ffi::lua_close(self.as_ptr())
+            let mut ud = ptr::null_mut();
+            let allocf = ffi::lua_getallocf(self.as_ptr(), &mut ud);
+            ffi::lua_close(self.as_ptr());
+            alloc::free_userdata(allocf, ud);
         }
     }
 }
@@ -860,6 +4861,7 @@ pub struct Info<'a> {
     idx: i32,
     tp: i32,
     name: &'a CStr,
+    state: NonNull<ffi::lua_State>,
 }
 
 impl<'a> Info<'a> {
@@ -873,10 +4875,61 @@ impl<'a> Info<'a> {
         self.tp
     }
 
+    /// Returns the value type.
+    pub fn value_type(&self) -> Type {
+        self.tp.into()
+    }
+
     /// Returns a reference to a C str with the name of the value type.
     pub fn type_name(&self) -> &'a CStr {
         self.name
     }
+
+    /// Returns a short rendering of the value, as printed by Lua for scalars and as the type name
+    /// followed by the address of the value otherwise, e.g. `table: 0x55d0c6a8e2c0`.
+    ///
+    /// The value is read as is: the stack is left unchanged and no metamethod, such as
+    /// `__tostring`, is invoked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.load_string("return nil, true, 42, 0.5, 'hi', {}").unwrap();
+    /// state.pcall(0, 6, 0).unwrap();
+    ///
+    /// let previews: Vec<_> = state.dump().map(|info| info.preview()).collect();
+    /// assert_eq!(previews[..5], ["nil", "true", "42", "0.5", "hi"]);
+    /// assert!(previews[5].starts_with("table: 0x"));
+    /// ```
+    pub fn preview(&self) -> String {
+        let state = self.state.as_ptr();
+        unsafe {
+            match self.tp {
+                ffi::LUA_TNONE | ffi::LUA_TNIL => self.name.to_string_lossy().into_owned(),
+                ffi::LUA_TBOOLEAN => (ffi::lua_toboolean(state, self.idx) != 0).to_string(),
+                ffi::LUA_TNUMBER if ffi::lua_isinteger(state, self.idx) != 0 => {
+                    ffi::lua_tointegerx(state, self.idx, ptr::null_mut()).to_string()
+                }
+                ffi::LUA_TNUMBER => {
+                    format!("{:?}", ffi::lua_tonumberx(state, self.idx, ptr::null_mut()))
+                }
+                // strings are not converted in place, unlike numbers
+                ffi::LUA_TSTRING => {
+                    let mut len = 0;
+                    let data = ffi::lua_tolstring(state, self.idx, &mut len) as *const u8;
+                    String::from_utf8_lossy(std::slice::from_raw_parts(data, len)).into_owned()
+                }
+                _ => {
+                    let ptr = ffi::lua_topointer(state, self.idx);
+                    format!("{}: {:p}", self.name.to_string_lossy(), ptr)
+                }
+            }
+        }
+    }
 }
 
 impl<'a> fmt::Display for Info<'a> {
@@ -894,14 +4947,28 @@ impl<'a> Iterator for Dump<'a> {
 
 /// A guard on the Lua stack size, that is it sets an expected low watermark for the stack size at
 /// creation and when it gets out of scope will pop the elements above this low watermark. When the
-/// stack size is below the low watermark it logs an error and terminates the process in an abnormal
-/// fashion.
+/// stack size is below the low watermark it acts according to its [`UnderflowPolicy`], by default
+/// it logs an error and terminates the process in an abnormal fashion.
 #[derive(Debug)]
 pub struct StackGuard<'a> {
     mark: i32,
+    policy: UnderflowPolicy,
     state: &'a mut State,
 }
 
+/// The behavior of a [`StackGuard`] when the stack size is below its low watermark once it gets out
+/// of scope.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum UnderflowPolicy {
+    /// Logs an error and terminates the process in an abnormal fashion.
+    #[default]
+    Abort,
+    /// Panics, unless the thread is already panicking.
+    Panic,
+    /// Logs a warning and leaves the stack as is.
+    Ignore,
+}
+
 impl<'a> StackGuard<'a> {
     /// Creates a new `StackGuard` with the stack size as low watermark.
     pub fn new(state: &'a mut State) -> Self {
@@ -911,7 +4978,46 @@ impl<'a> StackGuard<'a> {
 
     /// Creates a new `StackGuard` for a specified low watermark
     pub fn with_mark(mark: i32, state: &'a mut State) -> Self {
-        Self { state, mark }
+        Self {
+            state,
+            mark,
+            policy: UnderflowPolicy::default(),
+        }
+    }
+
+    /// Creates a new `StackGuard` with the stack size as low watermark and the specified underflow
+    /// `policy`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::{StackGuard, UnderflowPolicy};
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push_integer(1);
+    /// {
+    ///     let mut state = StackGuard::with_policy(&mut state, UnderflowPolicy::Ignore);
+    ///     state.pop(1);
+    /// }
+    /// assert_eq!(state.top(), 0);
+    /// ```
+    ///
+    /// ```should_panic
+    /// # extern crate lua;
+    /// use lua::state::{StackGuard, UnderflowPolicy};
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.push_integer(1);
+    /// let mut state = StackGuard::with_policy(&mut state, UnderflowPolicy::Panic);
+    /// state.pop(1);
+    /// ```
+    pub fn with_policy(state: &'a mut State, policy: UnderflowPolicy) -> Self {
+        let mut guard = Self::new(state);
+        guard.policy = policy;
+        guard
     }
 }
 
@@ -922,11 +5028,23 @@ impl<'a> Drop for StackGuard<'a> {
             debug!("[StackGuard] popping {} element(s)", top - self.mark);
             self.state.set_top(self.mark);
         } else if self.mark > top {
-            error!(
-                "[StackGuard] size ({}) under low watermark ({})",
-                top, self.mark
-            );
-            std::process::abort()
+            match self.policy {
+                UnderflowPolicy::Abort => {
+                    error!(
+                        "[StackGuard] size ({}) under low watermark ({})",
+                        top, self.mark
+                    );
+                    std::process::abort()
+                }
+                UnderflowPolicy::Panic if !std::thread::panicking() => panic!(
+                    "[StackGuard] size ({}) under low watermark ({})",
+                    top, self.mark
+                ),
+                _ => warn!(
+                    "[StackGuard] size ({}) under low watermark ({})",
+                    top, self.mark
+                ),
+            }
         }
     }
 }
@@ -956,19 +5074,221 @@ impl<'a> AsMut<State> for StackGuard<'a> {
     }
 }
 
+/// A guard on a value pushed onto the stack, that is it holds the result of the operation which
+/// pushed the value and when it gets out of scope restores the stack to the size it had before the
+/// value was pushed.
+#[derive(Debug)]
+pub struct Popped<'a, T> {
+    mark: i32,
+    value: T,
+    state: &'a mut State,
+}
+
+impl<'a, T> Popped<'a, T> {
+    fn new(mark: i32, value: T, state: &'a mut State) -> Self {
+        Self { mark, value, state }
+    }
+
+    /// Returns a reference to the result of the operation which pushed the value.
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'a, T> Drop for Popped<'a, T> {
+    fn drop(&mut self) {
+        if self.mark < self.state.top() {
+            self.state.set_top(self.mark);
+        }
+    }
+}
+
+impl<'a, T> Deref for Popped<'a, T> {
+    type Target = State;
+    fn deref(&self) -> &Self::Target {
+        self.state
+    }
+}
+
+impl<'a, T> DerefMut for Popped<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.state
+    }
+}
+
+/// A Lua thread, that is a coroutine with its own independent execution stack sharing the global
+/// environment of the state which created it.
+///
+/// This is created by [`State::new_thread`] or [`State::to_thread`] and borrows that state, so
+/// the borrow checker rejects any use of the thread after the state is dropped. The thread
+/// dereferences to a non-owning [`State`] which never closes the Lua state; threads are not closed
+/// explicitly, they are subject to garbage collection once dropped.
+///
+/// ```compile_fail
+/// # extern crate lua;
+/// use lua::State;
+///
+/// let thread = {
+///     let mut state = State::new();
+///     state.new_thread()
+/// };
+/// thread.top();
+/// ```
+#[derive(Debug)]
+pub struct Thread<'a> {
+    state: State,
+    reference: i32,
+    _parent: PhantomData<&'a mut State>,
+}
+
+impl<'a> Drop for Thread<'a> {
+    fn drop(&mut self) {
+        // the registry is shared by all the threads of a state
+        self.state.unreference(self.reference);
+    }
+}
+
+impl<'a> Deref for Thread<'a> {
+    type Target = State;
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl<'a> DerefMut for Thread<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.state
+    }
+}
+
+/// A non-owning view on a Lua state, created by [`State::view`].
+///
+/// The view mutably borrows the state it was created from, so the borrow checker rejects any
+/// other use of that state while the view is alive, and any use of the view after that state is
+/// dropped. It dereferences to a [`State`] sharing the same Lua state, which is never closed by the
+/// view.
+///
+/// ```compile_fail
+/// # extern crate lua;
+/// use lua::State;
+///
+/// let mut view = {
+///     let mut state = State::new();
+///     state.view()
+/// };
+/// view.push_integer(1);
+/// ```
+///
+/// ```compile_fail
+/// # extern crate lua;
+/// use lua::State;
+///
+/// let mut state = State::new();
+/// state.push_string("x").unwrap();
+/// let bytes = state.as_bytes(-1);
+/// let mut view = state.view();
+/// view.pop(1);
+/// assert_eq!(bytes, b"x");
+/// ```
+#[derive(Debug)]
+pub struct StateView<'a> {
+    state: State,
+    _parent: PhantomData<&'a mut State>,
+}
+
+impl<'a> Deref for StateView<'a> {
+    type Target = State;
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl<'a> DerefMut for StateView<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.state
+    }
+}
+
 /// A function defined in Lua.
+///
+/// Calling the function runs it in protected mode with a message handler, so that the error of a
+/// failed call includes a traceback of the stack at the point of the error.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use lua::state::Function;
+/// use lua::State;
+///
+/// let mut state = State::new();
+/// state.open_libs();
+/// state.do_string(r#"
+///     local function check(n)
+///         if n < 0 then error("negative") end
+///         return n
+///     end
+///     function sqrt(n) return math.sqrt(check(n)) end
+/// "#).unwrap();
+///
+/// let sqrt: Function<(f64,), f64> = Function::new(&mut state, "sqrt");
+/// assert_eq!(sqrt(4.0).unwrap(), 2.0);
+///
+/// let sqrt: Function<(f64,), f64> = Function::new(&mut state, "sqrt");
+/// let error = sqrt(-1.0).unwrap_err().to_string();
+/// assert!(error.contains("negative\nstack traceback:"));
+/// assert!(error.contains("in upvalue 'check'"));
+/// assert!(error.contains("in function 'sqrt'"));
+/// ```
 pub struct Function<'a, Args, Output> {
     state: &'a mut State,
-    name: &'a str,
+    callee: Callee<'a>,
     _marker: PhantomData<(Args, Output)>,
 }
 
+/// Where a [`Function`] finds the value to call.
+#[derive(Debug, Clone, Copy)]
+enum Callee<'a> {
+    /// The global with the given name.
+    Global(&'a str),
+    /// The value at the given absolute stack index.
+    Index(i32),
+}
+
 impl<'a, Args, Output> Function<'a, Args, Output> {
     /// Creates a new `Function` for given state and global name.
     pub fn new(state: &'a mut State, name: &'a str) -> Self {
         Self {
             state,
-            name,
+            callee: Callee::Global(name),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Creates a new `Function` calling the value at the given valid `index` of the stack, such
+    /// as a function stored in a table field or returned by another call. The value is left on
+    /// the stack and copied to the top for the call.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::state::Function;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// state.do_string("return { add = function(a, b) return a + b end }").unwrap();
+    /// state.get_field(-1, "add").unwrap();
+    ///
+    /// let add: Function<(i64, i64), i64> = Function::from_index(&mut state, -1);
+    /// assert_eq!(add(1, 2).unwrap(), 3);
+    /// assert_eq!(state.top(), 2);
+    /// ```
+    pub fn from_index(state: &'a mut State, index: i32) -> Self {
+        let index = state.abs_index(index);
+        Self {
+            state,
+            callee: Callee::Index(index),
             _marker: PhantomData,
         }
     }
@@ -979,12 +5299,21 @@ impl<'a, Args: Push, Output: Pull> FnOnce<Args> for Function<'a, Args, Output> {
     extern "rust-call" fn call_once(self, args: Args) -> Self::Output {
         let mut state = StackGuard::new(self.state);
 
-        // push functions and arguments
-        state.get_global(self.name)?; // push function
+        // push the message handler, below the function
+        state.push_cfunction(traceback_handler);
+        let msgh = state.top();
+
+        // push function and arguments
+        match self.callee {
+            Callee::Global(name) => {
+                state.get_global(name)?;
+            }
+            Callee::Index(index) => state.push_value(index),
+        }
         let nargs = args.push(&mut state)?;
 
         // do the call (2 arguments, 1 result)
-        state.pcall(nargs, ffi::LUA_MULTRET, 0)?;
+        state.pcall(nargs, ffi::LUA_MULTRET, msgh)?;
 
         // retrieve the result(s)
         Output::pull(&state, -1)
@@ -992,8 +5321,31 @@ impl<'a, Args: Push, Output: Pull> FnOnce<Args> for Function<'a, Args, Output> {
 }
 
 /// A Rust function wrapper.
+///
+/// Pushing the wrapper moves the function into a userdata owned by the pushed Lua function, which
+/// drops it once collected, so a `RustFunction` can only be pushed once.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate lua;
+/// use lua::state::{Push, RustFunction};
+/// use lua::State;
+///
+/// let suffix = String::from("!");
+/// let shout = RustFunction::new(move |s: String| Ok(s.to_uppercase() + &suffix));
+///
+/// let mut state = State::new();
+/// shout.push(&mut state).unwrap();
+/// state.set_global("shout").unwrap();
+/// assert!(shout.push(&mut state).is_err());
+///
+/// state.load_string("return shout('hi')").unwrap();
+/// state.pcall(0, 1, 0).unwrap();
+/// assert_eq!(state.as_bytes(-1), b"HI!");
+/// ```
 pub struct RustFunction<F, Args, Output> {
-    func: F,
+    func: Cell<Option<F>>,
     _marker: PhantomData<(Args, Output)>,
 }
 
@@ -1001,7 +5353,7 @@ impl<F, Args, Output> RustFunction<F, Args, Output> {
     /// Creates a new `RustFunction` wrapping specified func.
     pub fn new(func: F) -> Self {
         Self {
-            func,
+            func: Cell::new(Some(func)),
             _marker: PhantomData,
         }
     }
@@ -1009,21 +5361,168 @@ impl<F, Args, Output> RustFunction<F, Args, Output> {
 
 impl<F, Args, Output> Push for RustFunction<F, Args, Output>
 where
-    F: Fn(Args) -> Result<Output>,
+    F: Fn(Args) -> Result<Output> + 'static,
     Args: Pull,
     Output: Push,
 {
     fn push(&self, state: &mut State) -> Result<i32> {
-        unsafe {
-            let wrapped = wrapper::<Output, Args, F>;
+        let func = self
+            .func
+            .take()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "function already pushed"))?;
+
+        // the userdata owning the function is the upvalue of the C closure
+        state.push_userdata(func)?;
+        state.push_cclosure(wrapper::<Output, Args, F>, 1);
+        Ok(1)
+    }
+}
+
+/// A deferred push of a value onto the stack, returning the number of slots used.
+type PushFn<'a> = Box<dyn FnOnce(&mut State) -> Result<i32> + 'a>;
 
-            let ud = state.new_userdata(mem::size_of::<F>(), 1);
-            let func: &mut F = mem::transmute(ud);
-            ptr::copy(&self.func, func, 1);
+/// A builder for a library, that is a table of Rust functions registered as a global.
+///
+/// This is created by [`State::new_library`].
+pub struct LibraryBuilder<'a> {
+    state: &'a mut State,
+    name: String,
+    functions: Vec<(String, PushFn<'a>)>,
+}
 
-            state.push_cclosure(wrapped, 1);
+impl<'a> LibraryBuilder<'a> {
+    fn new(state: &'a mut State, name: &str) -> Self {
+        Self {
+            state,
+            name: name.to_string(),
+            functions: Vec::new(),
         }
-        Ok(1)
+    }
+
+    /// Adds the Rust function `f` to the library as the field `name`.
+    ///
+    /// Lua keeps the function for as long as the library is reachable, so it cannot borrow any
+    /// local; move the values it needs into it instead.
+    ///
+    /// ```compile_fail
+    /// # extern crate lua;
+    /// use lua::State;
+    ///
+    /// let mut state = State::new();
+    /// let factor = 3;
+    /// state
+    ///     .new_library("math2")
+    ///     .function("scale", |(n,): (i64,)| Ok(n * factor))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn function<Args, Output, F>(mut self, name: &str, f: F) -> Self
+    where
+        F: Fn(Args) -> Result<Output> + 'static,
+        Args: Pull + 'a,
+        Output: Push + 'a,
+    {
+        let push = move |state: &mut State| state.push(RustFunction::new(f));
+        self.functions.push((name.to_string(), Box::new(push)));
+        self
+    }
+
+    /// Creates the library table, populates it with the functions and sets it as the global
+    /// value of the library name.
+    pub fn build(self) -> Result<()> {
+        let mut state = StackGuard::new(self.state);
+        state.create_table(0, self.functions.len() as i32)?;
+        for (name, push) in self.functions {
+            push(&mut state)?;
+            state.set_field(-2, name)?;
+        }
+        state.set_global(self.name)
+    }
+}
+
+/// A builder for a C closure, that is a C function with upvalues.
+///
+/// This is created by [`State::closure`].
+pub struct ClosureBuilder<'a> {
+    state: &'a mut State,
+    function: CFunction,
+    upvalues: Vec<PushFn<'a>>,
+}
+
+impl<'a> ClosureBuilder<'a> {
+    fn new(state: &'a mut State, function: CFunction) -> Self {
+        Self {
+            state,
+            function,
+            upvalues: Vec::new(),
+        }
+    }
+
+    /// Adds `value` as the next upvalue of the closure, or as several upvalues when the value uses
+    /// several slots.
+    pub fn upvalue<T: Push + 'a>(mut self, value: T) -> Self {
+        let push = move |state: &mut State| value.push(state);
+        self.upvalues.push(Box::new(push));
+        self
+    }
+
+    /// Pushes the upvalues in order, then the closure in their place.
+    ///
+    /// Fails, leaving the stack unchanged, when an upvalue cannot be pushed or when there are more
+    /// than 255 upvalues, the maximum for a C closure.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate lua;
+    /// use lua::{ffi, State};
+    ///
+    /// unsafe extern "C" fn last(ptr: *mut ffi::lua_State) -> i32 {
+    ///     let mut state = State::from_ptr(ptr, false);
+    ///     state.push_value(state.upvalue_index(40));
+    ///     1
+    /// }
+    ///
+    /// let mut state = State::new();
+    /// let mut closure = state.closure(last);
+    /// for i in 1..=40 {
+    ///     closure = closure.upvalue(i as i64);
+    /// }
+    /// closure.build().unwrap();
+    /// state.pcall(0, 1, 0).unwrap();
+    /// assert_eq!(state.to_integer::<i64>(-1), Some(40));
+    ///
+    /// let mut closure = state.closure(last);
+    /// for i in 1..=256 {
+    ///     closure = closure.upvalue(i as i64);
+    /// }
+    /// assert!(closure.build().is_err());
+    /// assert_eq!(state.top(), 1);
+    /// ```
+    pub fn build(self) -> Result<()> {
+        let state = self.state;
+        let mark = state.top();
+        let mut n = 0;
+        for push in self.upvalues {
+            if !state.check_stack(ffi::LUA_MINSTACK) {
+                state.set_top(mark);
+                return Err(Error::new(ErrorKind::InvalidInput, "stack overflow"));
+            }
+            match push(state) {
+                Ok(slots) => n += slots,
+                Err(e) => {
+                    state.set_top(mark);
+                    return Err(e);
+                }
+            }
+        }
+        if n > 255 {
+            state.set_top(mark);
+            let msg = format!("too many upvalues ({}) for a C closure", n);
+            return Err(Error::new(ErrorKind::InvalidInput, msg));
+        }
+        state.push_cclosure(self.function, n);
+        Ok(())
     }
 }
 
@@ -1036,7 +5535,7 @@ where
     let mut state = State::from_ptr(ptr, false);
 
     let idx = state.upvalue_index(1);
-    let func: &mut F = mem::transmute(state.to_userdata(idx));
+    let func = &*align_userdata::<F>(state.to_userdata(idx));
 
     let ret = Args::pop(&mut state)
         .and_then(|args| func(args))
@@ -1056,3 +5555,196 @@ where
         }
     }
 }
+
+/// The registry field holding the userdata which owns the warning handler of a state.
+const WARN_HANDLER: &[u8] = b"lua.warn.handler\0";
+
+/// A function receiving a piece of a warning message and whether the message is to be continued.
+type WarnFn = dyn FnMut(&str, bool);
+
+/// A warning handler, with the state of the warning system.
+struct WarnHandler {
+    handler: Box<WarnFn>,
+    enabled: bool,
+    continued: bool,
+}
+
+impl WarnHandler {
+    fn new(handler: Box<WarnFn>) -> Self {
+        Self {
+            handler,
+            enabled: false,
+            continued: false,
+        }
+    }
+}
+
+unsafe extern "C" fn warn_handler(ud: *mut c_void, msg: *const libc::c_char, tocont: i32) {
+    let warn = &mut *(ud as *mut WarnHandler);
+    let msg = CStr::from_ptr(msg).to_string_lossy();
+    let tocont = tocont != 0;
+
+    // control messages are single-piece messages starting with '@'
+    if !warn.continued && !tocont && msg.starts_with('@') {
+        match &msg[1..] {
+            "on" => warn.enabled = true,
+            "off" => warn.enabled = false,
+            _ => {}
+        }
+        return;
+    }
+
+    warn.continued = tocont;
+    if warn.enabled {
+        (warn.handler)(&msg, tocont);
+    }
+}
+
+/// A function receiving the lines printed with the Lua function `print`.
+type PrintFn = dyn FnMut(&str);
+
+/// Prints its arguments to the sink set with [`State::set_print`], held by the first upvalue.
+unsafe extern "C" fn print(ptr: *mut ffi::lua_State) -> i32 {
+    let n = ffi::lua_gettop(ptr);
+    // each argument needs a slot for its string and one for the separator before it
+    ffi::luaL_checkstack(ptr, 2 * n, b"too many arguments\0".as_ptr() as *const i8);
+    for i in 1..=n {
+        if i > 1 {
+            ffi::lua_pushstring(ptr, b"\t\0".as_ptr() as *const i8);
+        }
+        ffi::luaL_tolstring(ptr, i, ptr::null_mut());
+    }
+    // concatenate in Lua, so no Rust value is alive when a `__tostring` metamethod raises an error
+    ffi::lua_concat(ptr, (2 * n - 1).max(0));
+
+    let mut len = 0;
+    let line = ffi::lua_tolstring(ptr, -1, &mut len) as *const u8;
+    let line = String::from_utf8_lossy(std::slice::from_raw_parts(line, len));
+    let block = ffi::lua_touserdata(ptr, ffi::lua_upvalueindex(1));
+    let sink = &mut *align_userdata::<Box<PrintFn>>(block);
+    sink(&line);
+    0
+}
+
+/// Drops the handler set by [`State::set_warn_handler`], as the `__gc` metamethod of its
+/// userdata.
+///
+/// Only the userdata kept in the registry is finalized, and its pointer is cleared, so a handler
+/// is never dropped twice, even when the finalizer is called by hand.
+unsafe extern "C" fn drop_warn_handler(ptr: *mut ffi::lua_State) -> i32 {
+    ffi::lua_getfield(
+        ptr,
+        ffi::LUA_REGISTRYINDEX,
+        WARN_HANDLER.as_ptr() as *const i8,
+    );
+    if ffi::lua_rawequal(ptr, 1, -1) == 0 {
+        return 0;
+    }
+    let ud = ffi::lua_touserdata(ptr, 1) as *mut *mut WarnHandler;
+    if !(*ud).is_null() {
+        ffi::lua_setwarnf(ptr, None, ptr::null_mut());
+        drop(Box::from_raw(*ud));
+        *ud = ptr::null_mut();
+    }
+    0
+}
+
+/// Message handler appending a traceback to the error message, like the standalone interpreter.
+unsafe extern "C" fn traceback_handler(ptr: *mut ffi::lua_State) -> i32 {
+    // converts a non-string error object with its `__tostring` metamethod, if any
+    let msg = ffi::luaL_tolstring(ptr, 1, ptr::null_mut());
+    ffi::luaL_traceback(ptr, ptr, msg, 1);
+    1
+}
+
+/// Returns the extra bytes needed to store a `T` at an aligned offset in a userdata memory block.
+fn userdata_padding<T>() -> usize {
+    // Lua aligns the memory blocks of userdata for its maximum alignment type, within blocks
+    // aligned by the allocator
+    let lua_align = mem::align_of::<ffi::lua_Number>()
+        .max(mem::align_of::<ffi::lua_Integer>())
+        .max(mem::align_of::<*mut c_void>());
+    let align = mem::align_of::<T>();
+    align.saturating_sub(lua_align.min(alloc::SYS_MIN_ALIGN))
+}
+
+/// Returns the address of a `T` stored at an aligned offset in the userdata memory `block`.
+fn align_userdata<T>(block: *mut c_void) -> *mut T {
+    let block = block as *mut u8;
+    block.wrapping_add(block.align_offset(mem::align_of::<T>())) as *mut T
+}
+
+/// Drops the `T` stored in the userdata at index 1, as the `__gc` metamethod of the metatable
+/// given as upvalue by [`State::push_userdata`].
+///
+/// The value is only dropped when the userdata has this metatable, which is then removed, so a
+/// value is never dropped twice nor as the wrong type, even when the finalizer is called by hand.
+unsafe extern "C" fn drop_userdata<T>(ptr: *mut ffi::lua_State) -> i32 {
+    let block = ffi::lua_touserdata(ptr, 1);
+    if block.is_null() || ffi::lua_getmetatable(ptr, 1) == 0 {
+        return 0;
+    }
+    let own = ffi::lua_rawequal(ptr, -1, ffi::lua_upvalueindex(1)) != 0;
+    ffi::lua_settop(ptr, 1);
+    if own {
+        ffi::lua_pushnil(ptr);
+        ffi::lua_setmetatable(ptr, 1);
+        ptr::drop_in_place(align_userdata::<T>(block));
+    }
+    0
+}
+
+/// Runs the closure behind the light userdata at index 1 for [`State::protect`], returning the
+/// values it pushed.
+unsafe extern "C" fn protected_trampoline<F: FnOnce(&mut State)>(ptr: *mut ffi::lua_State) -> i32 {
+    let f = &mut *(ffi::lua_touserdata(ptr, 1) as *mut Option<F>);
+    ffi::lua_settop(ptr, 0);
+    let mut state = State::from_ptr(ptr, false);
+    if let Some(f) = f.take() {
+        f(&mut state);
+    }
+    ffi::lua_gettop(ptr)
+}
+
+/// Calls the method named by the string at index 1 of the value at index 2, with that value and
+/// the values above it as arguments, for [`State::call_method`].
+unsafe extern "C" fn method_trampoline(ptr: *mut ffi::lua_State) -> i32 {
+    ffi::lua_pushvalue(ptr, 1);
+    ffi::lua_gettable(ptr, 2);
+    ffi::lua_replace(ptr, 1);
+    ffi::lua_call(ptr, ffi::lua_gettop(ptr) - 1, ffi::LUA_MULTRET);
+    ffi::lua_gettop(ptr)
+}
+
+/// Does `t[k] = v` with `t`, `k` and `v` at indices 1 to 3, for [`State::set_field_value`].
+unsafe extern "C" fn set_table_trampoline(ptr: *mut ffi::lua_State) -> i32 {
+    ffi::lua_settable(ptr, 1);
+    0
+}
+
+/// Pushes `t[k]` with `t` and `k` at indices 1 and 2, for [`State::get_field_value`].
+unsafe extern "C" fn get_table_trampoline(ptr: *mut ffi::lua_State) -> i32 {
+    ffi::lua_gettable(ptr, 1);
+    1
+}
+
+/// Runs the boxed continuation given as context to [`State::pcall_with_continuation`].
+unsafe extern "C" fn continuation<K>(
+    ptr: *mut ffi::lua_State,
+    code: i32,
+    ctx: ffi::lua_KContext,
+) -> i32
+where
+    K: FnOnce(&mut State, Result<()>) -> i32,
+{
+    let k = Box::from_raw(ctx as *mut K);
+    let mut state = State::from_ptr(ptr, false);
+    let result = state.handle_result(code, ());
+    k(&mut state, result)
+}
+
+/// Converts the value at index 1 to a string for [`State::to_string_meta`].
+unsafe extern "C" fn to_string_meta(ptr: *mut ffi::lua_State) -> i32 {
+    ffi::luaL_tostring(ptr, 1);
+    1
+}